@@ -0,0 +1,3 @@
+ALTER TABLE `users` CHANGE COLUMN `group` `role` VARCHAR(50) NOT NULL DEFAULT 'customer';
+UPDATE `users` SET `role` = 'admin' WHERE `role` = 'Admin';
+UPDATE `users` SET `role` = 'customer' WHERE `role` <> 'admin';
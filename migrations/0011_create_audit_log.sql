@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS `audit_log` (
+  `id` BIGINT NOT NULL AUTO_INCREMENT,
+  `actor_id` INT NULL,
+  `action` VARCHAR(50) NOT NULL,
+  `target_type` VARCHAR(20) NULL,
+  `target_id` VARCHAR(50) NULL,
+  `ip` VARCHAR(45) NOT NULL,
+  `user_agent` VARCHAR(255) NOT NULL,
+  `changes` JSON NULL,
+  `created_at` DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (`id`),
+  KEY `audit_log_actor` (`actor_id`),
+  KEY `audit_log_target` (`target_type`, `target_id`),
+  KEY `audit_log_action` (`action`, `created_at`)
+) ENGINE=InnoDB AUTO_INCREMENT=0 DEFAULT CHARSET=utf8mb4 COLLATE=utf8mb4_general_ci;
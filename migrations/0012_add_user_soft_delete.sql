@@ -0,0 +1,6 @@
+ALTER TABLE `users` ADD COLUMN `deletion_scheduled_at` DATETIME NULL DEFAULT NULL;
+ALTER TABLE `users` ADD COLUMN `anonymized_at` DATETIME NULL DEFAULT NULL;
+
+-- Orders are accounting records, deleting a user must never take them along
+ALTER TABLE `transaction_history` DROP FOREIGN KEY `transaction_history_ibfk_1`;
+ALTER TABLE `transaction_history` ADD CONSTRAINT `transaction_history_user` FOREIGN KEY (`user_id`) REFERENCES `users`(`id`) ON DELETE RESTRICT;
@@ -0,0 +1,2 @@
+-- Most copies of the book a single cart may hold, NULL falls back to `CART_MAX_ITEM_QUANTITY`
+ALTER TABLE `books` ADD COLUMN `max_cart_quantity` INT NULL DEFAULT NULL;
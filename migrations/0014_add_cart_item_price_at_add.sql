@@ -0,0 +1,4 @@
+-- The price of the book when it was put in the cart, checkout asks to confirm if it changed since
+ALTER TABLE `cart_items` ADD COLUMN `price_at_add` INT NULL DEFAULT NULL;
+
+UPDATE `cart_items` JOIN `books` ON `books`.`id` = `cart_items`.`book_id` SET `cart_items`.`price_at_add` = `books`.`price`;
@@ -1,12 +1,15 @@
 #![allow(unused, dead_code)]
+use redis::aio::ConnectionManager;
 use sqlx::{ConnectOptions, FromRow, MySql, MySqlConnection, Pool, Row};
 use std::error::Error;
 use std::time::Duration;
 
-#[derive(FromRow, Debug, Clone)]
+#[derive(FromRow, Clone)]
 pub struct Database {
     pub pool: Pool<MySql>,
-    pub redis: redis::Client
+    pub redis: ConnectionManager,
+    // Blocking commands need their own connection, e.g. the mail worker
+    pub redis_client: redis::Client,
     // pub connection: MySqlConnection,
 }
 
@@ -20,7 +23,8 @@ impl Database {
             .connect(sql_url)
             .await?;
 
-        let redis = redis::Client::open(redis_url).unwrap();
+        let redis_client = redis::Client::open(redis_url)?;
+        let redis = ConnectionManager::new(redis_client.clone()).await?;
         // let mut redis = client.get_connection().unwrap();
 
         // sqlx::migrate!("./migrations").run(&pool).await?;
@@ -34,6 +38,10 @@ impl Database {
         //     .connect()
         //     .await?;
 
-        Ok(Database { pool, redis })
+        Ok(Database {
+            pool,
+            redis,
+            redis_client,
+        })
     }
 }
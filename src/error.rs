@@ -0,0 +1,202 @@
+use actix_web::{
+    http::{header::RETRY_AFTER, StatusCode},
+    HttpResponse, ResponseError,
+};
+use serde::Serialize;
+use std::fmt;
+use validator::{ValidationError, ValidationErrors};
+
+use crate::i18n::Locale;
+
+// Every error the API returns, the code is also the key of the message in the catalogs
+#[derive(Debug)]
+pub enum AppError {
+    BadRequest(&'static str),
+    Unauthorized(&'static str),
+    Forbidden(&'static str),
+    NotFound(&'static str),
+    Conflict(&'static str),
+    Unprocessable(&'static str, Option<serde_json::Value>),
+    // Every invalid field of a request body, listed in the details
+    Validation(ValidationErrors),
+    // The second field is the number of seconds to wait
+    TooManyRequests(&'static str, i64),
+    // The cause is only logged, the client gets a generic message
+    Internal(String),
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorBody {
+    pub code: &'static str,
+    pub message: String,
+    pub details: Option<serde_json::Value>,
+}
+
+impl AppError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::BadRequest(code)
+            | AppError::Unauthorized(code)
+            | AppError::Forbidden(code)
+            | AppError::NotFound(code)
+            | AppError::Conflict(code)
+            | AppError::Unprocessable(code, _)
+            | AppError::TooManyRequests(code, _) => code,
+            AppError::Validation(_) => "validation_failed",
+            AppError::Internal(_) => "internal_error",
+        }
+    }
+
+    pub fn message(&self, locale: Locale) -> String {
+        match self {
+            AppError::TooManyRequests(code, retry_after) => {
+                locale.t_with(code, &[("seconds", retry_after.to_string())])
+            }
+            _ => locale.t(self.code()),
+        }
+    }
+
+    // The error response in the language of the client
+    pub fn to_response(&self, locale: Locale) -> HttpResponse {
+        if let AppError::Internal(cause) = self {
+            log::error!("Belső hiba: {}", cause);
+        }
+
+        let mut response = HttpResponse::build(self.status_code());
+        if let AppError::TooManyRequests(_, retry_after) = self {
+            response.insert_header((RETRY_AFTER, retry_after.to_string()));
+        }
+
+        let details = match self {
+            AppError::Unprocessable(_, details) => details.clone(),
+            AppError::Validation(errors) => Some(Self::field_errors(errors, locale)),
+            _ => None,
+        };
+        response.json(ErrorBody {
+            code: self.code(),
+            message: self.message(locale),
+            details,
+        })
+    }
+
+    fn field_errors(errors: &ValidationErrors, locale: Locale) -> serde_json::Value {
+        let mut fields = errors.field_errors().into_iter().collect::<Vec<_>>();
+        fields.sort_by_key(|(field, _)| *field);
+
+        let details = fields
+            .into_iter()
+            .flat_map(|(field, errors)| {
+                errors.iter().map(move |error| {
+                    serde_json::json!({
+                        "field": field,
+                        "code": error.code,
+                        "message": Self::field_error_message(error, locale),
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        serde_json::Value::Array(details)
+    }
+
+    // Only the bounds go into the message, the submitted value is never echoed back
+    fn field_error_message(error: &ValidationError, locale: Locale) -> String {
+        let args = ["min", "max", "equal"]
+            .into_iter()
+            .filter_map(|name| Some((name, error.params.get(name)?.to_string())))
+            .collect::<Vec<_>>();
+
+        let key = match (error.code.as_ref(), args.as_slice()) {
+            ("length" | "range", [(bound, _)]) => format!("validation_{}_{}", error.code, bound),
+            _ => format!("validation_{}", error.code),
+        };
+        locale.t_with(&key, &args)
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Internal(cause) => write!(f, "{}", cause),
+            _ => write!(f, "{}", self.message(Locale::default())),
+        }
+    }
+}
+
+// Rendered in the default language here, the `Localize` middleware translates it
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::Unprocessable(..) | AppError::Validation(_) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            AppError::TooManyRequests(..) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        self.to_response(Locale::default())
+    }
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(e: sqlx::Error) -> Self {
+        match e {
+            sqlx::Error::RowNotFound => AppError::NotFound("not_found"),
+            e => AppError::Internal(e.to_string()),
+        }
+    }
+}
+
+impl From<ValidationErrors> for AppError {
+    fn from(e: ValidationErrors) -> Self {
+        AppError::Validation(e)
+    }
+}
+
+impl From<redis::RedisError> for AppError {
+    fn from(e: redis::RedisError) -> Self {
+        AppError::Internal(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for AppError {
+    fn from(e: serde_json::Error) -> Self {
+        AppError::Internal(e.to_string())
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for AppError {
+    fn from(e: jsonwebtoken::errors::Error) -> Self {
+        AppError::Internal(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for AppError {
+    fn from(e: std::io::Error) -> Self {
+        AppError::Internal(e.to_string())
+    }
+}
+
+impl From<csv::Error> for AppError {
+    fn from(e: csv::Error) -> Self {
+        AppError::Internal(e.to_string())
+    }
+}
+
+impl From<zip::result::ZipError> for AppError {
+    fn from(e: zip::result::ZipError) -> Self {
+        AppError::Internal(e.to_string())
+    }
+}
+
+impl From<Box<dyn std::error::Error>> for AppError {
+    fn from(e: Box<dyn std::error::Error>) -> Self {
+        AppError::Internal(e.to_string())
+    }
+}
@@ -0,0 +1,14 @@
+use actix_web::{dev::Payload, FromRequest, HttpRequest};
+use std::convert::Infallible;
+use std::future::{ready, Ready};
+
+use crate::models::audit_log::AuditContext;
+
+impl FromRequest for AuditContext {
+    type Error = Infallible;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Ok(AuditContext::from_request(req)))
+    }
+}
@@ -1,67 +1,98 @@
-use actix_web::{
-    dev::Payload, error::ErrorUnauthorized, http::header::HeaderValue, web, Error as ActixWebError,
-    FromRequest, HttpRequest,
-};
-use jsonwebtoken::{
-    decode, errors::Error as JwtError, Algorithm, DecodingKey, TokenData, Validation,
-};
+use actix_web::{dev::Payload, http::header::HeaderValue, web, FromRequest, HttpRequest};
 use serde::{Deserialize, Serialize};
-use std::future::{ready, Ready};
+use std::future::Future;
+use std::pin::Pin;
 
+use crate::database::Database;
+use crate::error::AppError;
+use crate::models::user::User;
 use crate::server::WebData;
+use crate::utils::{
+    jwt::{decode_jwt_token, is_jti_revoked},
+    refresh_token::RefreshToken,
+};
 
 #[derive(Serialize, Deserialize)]
 pub struct Claims {
+    pub iss: String,
+    pub aud: String,
+    // The user id as a string, `id` holds the same as a number
+    pub sub: String,
+    pub iat: usize,
     pub id: usize,
     pub exp: usize,
+    pub jti: String,
+    pub sid: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AuthenticationToken {
     pub id: usize,
+    pub jti: String,
+    pub sid: String,
+    pub exp: usize,
 }
 
 impl FromRequest for AuthenticationToken {
-    type Error = ActixWebError;
-    type Future = Ready<Result<Self, Self::Error>>;
+    type Error = AppError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
 
     fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
         let req = req.clone();
 
-        let authorization_header_option: Option<&HeaderValue> =
-            req.headers().get(actix_web::http::header::AUTHORIZATION);
+        Box::pin(async move {
+            let authorization_header_option: Option<&HeaderValue> =
+                req.headers().get(actix_web::http::header::AUTHORIZATION);
+
+            // No Header was sent
+            if authorization_header_option.is_none() {
+                return Err(AppError::Unauthorized("missing_auth_token"));
+            }
+
+            let authentication_token: String = authorization_header_option
+                .unwrap()
+                .to_str()
+                .unwrap_or("")
+                .to_string();
+
+            // Couldn't convert Header::Authorization to String
+            if authentication_token.is_empty() {
+                return Err(AppError::Unauthorized("invalid_auth_token"));
+            }
 
-        // No Header was sent
-        if authorization_header_option.is_none() {
-            return ready(Err(ErrorUnauthorized("No authentication token sent!")));
-        }
+            let jwt_keys = &req.app_data::<web::Data<WebData>>().unwrap().jwt_keys;
 
-        let authentication_token: String = authorization_header_option
-            .unwrap()
-            .to_str()
-            .unwrap_or("")
-            .to_string();
+            let claims = match decode_jwt_token(&authentication_token, jwt_keys) {
+                Ok(claims) => claims,
+                Err(_e) => return Err(AppError::Unauthorized("invalid_auth_token")),
+            };
 
-        // Couldn't convert Header::Authorization to String
-        if authentication_token.is_empty() {
-            return ready(Err(ErrorUnauthorized(
-                "Authentication token has foreign chars!",
-            )));
-        }
+            // Tokens of logged out sessions are denied until they expire
+            let mut redis_con = req.app_data::<web::Data<Database>>().unwrap().redis.clone();
+            let is_revoked = match is_jti_revoked(&mut redis_con, &claims.jti).await {
+                Ok(true) => Ok(true),
+                Ok(false) => RefreshToken::touch_session(&mut redis_con, &claims.sid)
+                    .await
+                    .map(|is_live| !is_live),
+                Err(e) => Err(e),
+            };
 
-        let secret: &str = &req.app_data::<web::Data<WebData>>().unwrap().auth_secret;
+            match is_revoked {
+                Ok(false) => {}
+                Ok(true) => return Err(AppError::Unauthorized("revoked_auth_token")),
+                Err(e) => return Err(AppError::from(e)),
+            }
 
-        let token_result: Result<TokenData<Claims>, JwtError> = decode::<Claims>(
-            &authentication_token,
-            &DecodingKey::from_secret(secret.as_ref()),
-            &Validation::new(Algorithm::HS256),
-        );
+            // Accounts disabled by an admin are locked out right away
+            let db = req.app_data::<web::Data<Database>>().unwrap();
+            User::ensure_enabled(db, claims.id as i32).await?;
 
-        match token_result {
-            Ok(token) => ready(Ok(AuthenticationToken {
-                id: token.claims.id,
-            })),
-            Err(_e) => ready(Err(ErrorUnauthorized("Invalid authentication token sent!"))),
-        }
+            Ok(AuthenticationToken {
+                id: claims.id,
+                jti: claims.jti,
+                sid: claims.sid,
+                exp: claims.exp,
+            })
+        })
     }
 }
@@ -0,0 +1,93 @@
+use actix_web::{dev::Payload, web, FromRequest, HttpRequest};
+use std::future::{ready, Ready};
+
+use crate::error::AppError;
+use crate::server::WebData;
+use crate::utils::{email::Token, signing::Signer};
+
+pub const CART_TOKEN_HEADER: &str = "x-cart-token";
+
+// The cart of a visitor who isn't logged in, sent as `<cart id>.<signature>` in the
+// `X-Cart-Token` header
+#[derive(Debug)]
+pub struct GuestCartToken {
+    pub cart_id: String,
+}
+
+impl GuestCartToken {
+    // A new cart id and the token to hand out for it
+    pub fn issue(signer: &Signer) -> (Self, String) {
+        let cart_id = Token::generate_reset_token();
+        let token = format!("{cart_id}.{}", signer.sign(&cart_id));
+        (Self { cart_id }, token)
+    }
+
+    fn parse(req: &HttpRequest) -> Result<Self, AppError> {
+        let Some(header) = req.headers().get(CART_TOKEN_HEADER) else {
+            return Err(AppError::Unauthorized("missing_cart_token"));
+        };
+
+        let signer = &req.app_data::<web::Data<WebData>>().unwrap().cart_tokens;
+        let token = header
+            .to_str()
+            .map_err(|_| AppError::Unauthorized("invalid_cart_token"))?;
+        Self::verify(signer, token)
+    }
+
+    fn verify(signer: &Signer, token: &str) -> Result<Self, AppError> {
+        let (cart_id, signature) = token
+            .split_once('.')
+            .ok_or(AppError::Unauthorized("invalid_cart_token"))?;
+        if !signer.verify(cart_id, signature) {
+            return Err(AppError::Unauthorized("invalid_cart_token"));
+        }
+
+        Ok(Self {
+            cart_id: cart_id.to_string(),
+        })
+    }
+}
+
+impl FromRequest for GuestCartToken {
+    type Error = AppError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Self::parse(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cart_id_of(signer: &Signer, token: &str) -> Option<String> {
+        GuestCartToken::verify(signer, token)
+            .ok()
+            .map(|token| token.cart_id)
+    }
+
+    #[test]
+    fn issued_token_is_accepted() {
+        let signer = Signer::new("cart-secret", "guest-cart");
+        let (cart, token) = GuestCartToken::issue(&signer);
+        assert_eq!(cart_id_of(&signer, &token), Some(cart.cart_id));
+    }
+
+    #[test]
+    fn forged_tokens_are_rejected() {
+        let signer = Signer::new("cart-secret", "guest-cart");
+        let (_, token) = GuestCartToken::issue(&signer);
+        let (cart_id, signature) = token.split_once('.').unwrap();
+
+        assert_eq!(
+            cart_id_of(&signer, &format!("other{cart_id}.{signature}")),
+            None
+        );
+        assert_eq!(cart_id_of(&signer, cart_id), None);
+        assert_eq!(
+            cart_id_of(&Signer::new("other-secret", "guest-cart"), &token),
+            None
+        );
+    }
+}
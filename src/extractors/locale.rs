@@ -0,0 +1,14 @@
+use actix_web::{dev::Payload, FromRequest, HttpRequest};
+use std::convert::Infallible;
+use std::future::{ready, Ready};
+
+use crate::i18n::Locale;
+
+impl FromRequest for Locale {
+    type Error = Infallible;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Ok(Locale::of(req)))
+    }
+}
@@ -1 +1,5 @@
+pub mod audit_context;
 pub mod authentication_token;
+pub mod guest_cart_token;
+pub mod locale;
+pub mod validated_json;
@@ -0,0 +1,135 @@
+use actix_web::{http::header::ACCEPT_LANGUAGE, HttpRequest};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+// The catalogs are compiled into the binary, every key of `hu` should exist in `en` too
+const HU_CATALOG: &str = include_str!("../locales/hu.json");
+const EN_CATALOG: &str = include_str!("../locales/en.json");
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Locale {
+    #[default]
+    Hu,
+    En,
+}
+
+impl Locale {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Locale::Hu => "hu",
+            Locale::En => "en",
+        }
+    }
+
+    // Accepts language tags like `en`, `en-US` or `hu_HU`
+    pub fn parse(tag: &str) -> Option<Self> {
+        let language = tag.trim().split(['-', '_']).next()?.to_lowercase();
+        match language.as_str() {
+            "hu" => Some(Locale::Hu),
+            "en" => Some(Locale::En),
+            _ => None,
+        }
+    }
+
+    // Pick the supported language with the highest q value, Hungarian if none matches
+    pub fn from_accept_language(header: &str) -> Self {
+        header
+            .split(',')
+            .filter_map(|entry| {
+                let mut parts = entry.split(';');
+                let locale = Self::parse(parts.next()?)?;
+                let quality = parts
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                (quality > 0.0).then_some((locale, quality))
+            })
+            .fold(
+                None,
+                |best: Option<(Locale, f32)>, (locale, quality)| match best {
+                    Some((_, best_quality)) if best_quality >= quality => best,
+                    _ => Some((locale, quality)),
+                },
+            )
+            .map(|(locale, _)| locale)
+            .unwrap_or_default()
+    }
+
+    // The locale the client asked for in the request
+    pub fn of(req: &HttpRequest) -> Self {
+        req.headers()
+            .get(ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok())
+            .map(Self::from_accept_language)
+            .unwrap_or_default()
+    }
+
+    // Look up a message, falls back to Hungarian and then to the key itself
+    pub fn t(&self, key: &str) -> String {
+        self.catalog()
+            .get(key)
+            .or_else(|| Locale::Hu.catalog().get(key))
+            .cloned()
+            .unwrap_or_else(|| key.to_string())
+    }
+
+    // Look up a message and fill in its `{name}` placeholders
+    pub fn t_with(&self, key: &str, args: &[(&str, String)]) -> String {
+        args.iter().fold(self.t(key), |message, (name, value)| {
+            message.replace(&format!("{{{name}}}"), value)
+        })
+    }
+
+    fn catalog(&self) -> &'static HashMap<String, String> {
+        static HU: OnceLock<HashMap<String, String>> = OnceLock::new();
+        static EN: OnceLock<HashMap<String, String>> = OnceLock::new();
+
+        let (cell, source) = match self {
+            Locale::Hu => (&HU, HU_CATALOG),
+            Locale::En => (&EN, EN_CATALOG),
+        };
+        cell.get_or_init(|| serde_json::from_str(source).expect("Invalid message catalog"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn catalogs_have_the_same_keys() {
+        let keys = |locale: Locale| locale.catalog().keys().cloned().collect::<BTreeSet<_>>();
+        // Listed on failure, so a missing translation is easy to spot
+        let hu = keys(Locale::Hu);
+        let en = keys(Locale::En);
+        assert_eq!(hu.difference(&en).collect::<Vec<_>>(), [] as [&String; 0]);
+        assert_eq!(en.difference(&hu).collect::<Vec<_>>(), [] as [&String; 0]);
+    }
+
+    #[test]
+    fn highest_quality_supported_language_wins() {
+        assert_eq!(Locale::from_accept_language("en-US,en;q=0.9"), Locale::En);
+        assert_eq!(
+            Locale::from_accept_language("de;q=1, en;q=0.5, hu;q=0.8"),
+            Locale::Hu
+        );
+        assert_eq!(
+            Locale::from_accept_language("hu;q=0, en_GB;q=0.1"),
+            Locale::En
+        );
+        assert_eq!(Locale::from_accept_language("de, fr"), Locale::Hu);
+        assert_eq!(Locale::from_accept_language(""), Locale::Hu);
+    }
+
+    #[test]
+    fn placeholders_are_filled_and_missing_keys_fall_back_to_the_key() {
+        assert_eq!(
+            Locale::En.t_with("login_locked", &[("seconds", "30".to_string())]),
+            "Too many failed attempts, please try again in 30 seconds"
+        );
+        assert_eq!(Locale::En.t("no_such_key"), "no_such_key");
+    }
+}
@@ -1,8 +1,11 @@
 mod extractors;
+mod middlewares;
 mod models;
 mod scopes;
 
 mod database;
+mod error;
+mod i18n;
 
 mod utils;
 
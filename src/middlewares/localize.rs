@@ -0,0 +1,73 @@
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderValue, CONTENT_LANGUAGE},
+    Error,
+};
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+use crate::error::AppError;
+use crate::i18n::Locale;
+
+// Renders the errors of the handlers and extractors in the language of the client
+pub struct Localize;
+
+impl<S, B> Transform<S, ServiceRequest> for Localize
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = LocalizeMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(LocalizeMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct LocalizeMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for LocalizeMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let locale = Locale::of(req.request());
+
+        Box::pin(async move {
+            let res = service.call(req).await?;
+
+            // Only responses made from an `AppError` are rebuilt, everything else passes through
+            let localized = res
+                .response()
+                .error()
+                .and_then(|e| e.as_error::<AppError>())
+                .map(|e| e.to_response(locale));
+            let mut res = match localized {
+                Some(response) => res.into_response(response).map_into_right_body(),
+                None => res.map_into_left_body(),
+            };
+
+            res.headers_mut()
+                .insert(CONTENT_LANGUAGE, HeaderValue::from_static(locale.code()));
+            Ok(res)
+        })
+    }
+}
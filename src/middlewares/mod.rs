@@ -0,0 +1,3 @@
+pub mod localize;
+pub mod rate_limit;
+pub mod require_permission;
@@ -0,0 +1,304 @@
+extern crate redis;
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderName, HeaderValue, AUTHORIZATION},
+    web, Error,
+};
+use redis::aio::ConnectionManager;
+use std::env;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::str::FromStr;
+
+use crate::database::Database;
+use crate::error::AppError;
+use crate::i18n::Locale;
+use crate::server::WebData;
+use crate::utils::{client_ip::ClientIp, email::Token, jwt::decode_jwt_token};
+
+pub const RATELIMIT_LIMIT: &str = "ratelimit-limit";
+pub const RATELIMIT_REMAINING: &str = "ratelimit-remaining";
+pub const RATELIMIT_RESET: &str = "ratelimit-reset";
+
+// Sliding window log: drop the entries older than the window, then count the rest
+const SLIDING_WINDOW_SCRIPT: &str = r"
+local now = tonumber(ARGV[1])
+local window = tonumber(ARGV[2])
+local limit = tonumber(ARGV[3])
+redis.call('ZREMRANGEBYSCORE', KEYS[1], 0, now - window)
+local count = redis.call('ZCARD', KEYS[1])
+if count < limit then
+    redis.call('ZADD', KEYS[1], now, ARGV[4])
+    redis.call('PEXPIRE', KEYS[1], window)
+    return {1, limit - count - 1, window}
+end
+local oldest = redis.call('ZRANGE', KEYS[1], 0, 0, 'WITHSCORES')
+if oldest[2] == nil then
+    return {0, 0, window}
+end
+return {0, 0, tonumber(oldest[2]) + window - now}
+";
+
+// Who the requests are counted for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitIdentity {
+    Ip,
+    // Falls back to the IP address for anonymous requests
+    User,
+}
+
+impl FromStr for RateLimitIdentity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ip" => Ok(RateLimitIdentity::Ip),
+            "user" => Ok(RateLimitIdentity::User),
+            other => Err(format!("Unknown rate limit identity: {other}")),
+        }
+    }
+}
+
+// The parts of a `RATE_LIMIT_<GROUP>` value, a missing or empty part keeps the default
+type RateLimitOverride = (Option<u64>, Option<u64>, Option<RateLimitIdentity>);
+
+fn parse_override(config: &str) -> Result<RateLimitOverride, String> {
+    fn part<T: FromStr>(part: Option<&str>) -> Result<Option<T>, ()> {
+        match part.map(str::trim) {
+            None | Some("") => Ok(None),
+            Some(part) => part.parse().map(Some).map_err(|_| ()),
+        }
+    }
+
+    let invalid = || format!("Invalid rate limit: {config}");
+    let parts = config.split('/').collect::<Vec<&str>>();
+    if parts.len() > 3 {
+        return Err(invalid());
+    }
+    let limit = part::<u64>(parts.first().copied()).map_err(|_| invalid())?;
+    let window_secs = part::<u64>(parts.get(1).copied()).map_err(|_| invalid())?;
+    let identity = part::<RateLimitIdentity>(parts.get(2).copied()).map_err(|_| invalid())?;
+    if limit == Some(0) || window_secs == Some(0) {
+        return Err(invalid());
+    }
+    Ok((limit, window_secs, identity))
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    group: String,
+    limit: u64,
+    window_secs: u64,
+    identity: RateLimitIdentity,
+}
+
+impl RateLimit {
+    // Limits of a route group, `RATE_LIMIT_<GROUP>=limit/window_secs/ip|user` overrides the defaults
+    pub fn new(group: &str, limit: u64, window_secs: u64, identity: RateLimitIdentity) -> Self {
+        let mut rate_limit = Self {
+            group: group.to_string(),
+            limit,
+            window_secs,
+            identity,
+        };
+
+        // A malformed value already stopped the server in `check_env`
+        let Some((limit, window_secs, identity)) =
+            env::var(format!("RATE_LIMIT_{}", group.to_uppercase()))
+                .ok()
+                .and_then(|config| parse_override(&config).ok())
+        else {
+            return rate_limit;
+        };
+        rate_limit.limit = limit.unwrap_or(rate_limit.limit);
+        rate_limit.window_secs = window_secs.unwrap_or(rate_limit.window_secs);
+        rate_limit.identity = identity.unwrap_or(rate_limit.identity);
+        rate_limit
+    }
+
+    // Check every `RATE_LIMIT_<GROUP>` at startup, the limits are built later in each worker
+    pub fn check_env() -> Result<(), String> {
+        for (name, config) in env::vars().filter(|(name, _)| name.starts_with("RATE_LIMIT_")) {
+            parse_override(&config).map_err(|_| format!("Invalid {name}: {config}"))?;
+        }
+        Ok(())
+    }
+
+    fn identity_of(&self, req: &ServiceRequest) -> String {
+        if self.identity == RateLimitIdentity::User {
+            if let Some(user_id) = Self::user_id_of(req) {
+                return format!("user:{user_id}");
+            }
+        }
+
+        format!("ip:{}", ClientIp::of(req.request()))
+    }
+
+    // Only the signature is checked, revoked tokens are rejected later by the extractor
+    fn user_id_of(req: &ServiceRequest) -> Option<usize> {
+        let token = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+        let jwt_keys = &req.app_data::<web::Data<WebData>>()?.jwt_keys;
+
+        decode_jwt_token(token, jwt_keys)
+            .ok()
+            .map(|claims| claims.id)
+    }
+
+    // Returns if the request is allowed, the remaining requests and the milliseconds until reset
+    async fn hit(
+        &self,
+        con: &mut ConnectionManager,
+        identity: &str,
+    ) -> redis::RedisResult<(bool, u64, u64)> {
+        let (allowed, remaining, reset_ms) = redis::Script::new(SLIDING_WINDOW_SCRIPT)
+            .key(format!("rate-limit:{}:{identity}", self.group))
+            .arg(chrono::Utc::now().timestamp_millis())
+            .arg(self.window_secs * 1000)
+            .arg(self.limit)
+            .arg(Token::generate_reset_token())
+            .invoke_async::<(i64, i64, i64)>(con)
+            .await?;
+
+        Ok((
+            allowed == 1,
+            remaining.max(0) as u64,
+            reset_ms.max(0) as u64,
+        ))
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            rate_limit: Rc::new(self.clone()),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    rate_limit: Rc<RateLimit>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let rate_limit = Rc::clone(&self.rate_limit);
+
+        Box::pin(async move {
+            let identity = rate_limit.identity_of(&req);
+            let mut redis_con = req.app_data::<web::Data<Database>>().unwrap().redis.clone();
+            let result = rate_limit.hit(&mut redis_con, &identity).await;
+
+            // Don't take the whole API down with Redis, let the request through
+            let (allowed, remaining, reset_ms) = match result {
+                Ok(result) => result,
+                Err(e) => {
+                    log::warn!("Rate limit hiba: {:?}", e);
+                    let res = service.call(req).await?;
+                    return Ok(res.map_into_left_body());
+                }
+            };
+            let reset_secs = reset_ms.div_ceil(1000);
+
+            let mut res = if allowed {
+                service.call(req).await?.map_into_left_body()
+            } else {
+                let response = AppError::TooManyRequests("rate_limited", reset_secs as i64)
+                    .to_response(Locale::of(req.request()));
+                req.into_response(response).map_into_right_body()
+            };
+
+            // A stricter route group limit wrapped inside keeps its own headers
+            let headers = res.headers_mut();
+            for (name, value) in [
+                (RATELIMIT_LIMIT, rate_limit.limit),
+                (RATELIMIT_REMAINING, remaining),
+                (RATELIMIT_RESET, reset_secs),
+            ] {
+                let name = HeaderName::from_static(name);
+                if !headers.contains_key(&name) {
+                    headers.insert(name, HeaderValue::from(value));
+                }
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn config(rate_limit: &RateLimit) -> (u64, u64, RateLimitIdentity) {
+        (
+            rate_limit.limit,
+            rate_limit.window_secs,
+            rate_limit.identity,
+        )
+    }
+
+    #[test]
+    fn group_limits_can_be_overridden_from_the_environment() {
+        let rate_limit = RateLimit::new("test_defaults", 10, 60, RateLimitIdentity::Ip);
+        assert_eq!(config(&rate_limit), (10, 60, RateLimitIdentity::Ip));
+
+        env::set_var("RATE_LIMIT_TEST_OVERRIDE", "3/900/user");
+        let rate_limit = RateLimit::new("test_override", 10, 60, RateLimitIdentity::Ip);
+        assert_eq!(config(&rate_limit), (3, 900, RateLimitIdentity::User));
+
+        // Empty parts keep their defaults
+        env::set_var("RATE_LIMIT_TEST_PARTIAL", "/120");
+        let rate_limit = RateLimit::new("test_partial", 10, 60, RateLimitIdentity::User);
+        assert_eq!(config(&rate_limit), (10, 120, RateLimitIdentity::User));
+    }
+
+    #[test]
+    fn malformed_overrides_are_rejected() {
+        assert_eq!(parse_override("3"), Ok((Some(3), None, None)));
+        assert_eq!(
+            parse_override("3/900/USER"),
+            Ok((Some(3), Some(900), Some(RateLimitIdentity::User)))
+        );
+        for config in ["x/120", "3/1m", "3/900/admin", "0/60", "3/0", "3/900/ip/x"] {
+            assert!(parse_override(config).is_err(), "{config}");
+        }
+    }
+
+    #[test]
+    fn anonymous_requests_are_counted_by_the_peer_address() {
+        let req = TestRequest::default()
+            .peer_addr("203.0.113.9:4000".parse().unwrap())
+            .insert_header(("x-forwarded-for", "198.51.100.1"))
+            .to_srv_request();
+
+        for identity in [RateLimitIdentity::Ip, RateLimitIdentity::User] {
+            let rate_limit = RateLimit::new("test_anonymous", 10, 60, identity);
+            assert_eq!(rate_limit.identity_of(&req), "ip:203.0.113.9");
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    web, Error, FromRequest,
+};
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+use crate::database::Database;
+use crate::error::AppError;
+use crate::extractors::authentication_token::AuthenticationToken;
+use crate::models::{role::Permission, two_factor::TwoFactor, user::User};
+
+// Lets the request through only if the role of the user has the permission, the role is
+// read on every request, so a changed role applies right away
+#[derive(Debug, Clone, Copy)]
+pub struct RequirePermission(pub Permission);
+
+impl RequirePermission {
+    async fn authorize(&self, req: &ServiceRequest) -> Result<(), AppError> {
+        let auth_token = AuthenticationToken::extract(req.request()).await?;
+        let db = req.app_data::<web::Data<Database>>().unwrap();
+        let user_id = auth_token.id as i32;
+
+        if !User::get_role(db, user_id).await?.has_permission(self.0) {
+            return Err(AppError::Forbidden("forbidden"));
+        }
+        // Staff who were forced to enrol can only use the guarded routes after setting up 2FA
+        if TwoFactor::is_setup_pending(db, user_id).await? {
+            return Err(AppError::Forbidden("two_factor_setup_required"));
+        }
+        Ok(())
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequirePermission
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RequirePermissionMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequirePermissionMiddleware {
+            service: Rc::new(service),
+            guard: *self,
+        }))
+    }
+}
+
+pub struct RequirePermissionMiddleware<S> {
+    service: Rc<S>,
+    guard: RequirePermission,
+}
+
+impl<S, B> Service<ServiceRequest> for RequirePermissionMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let guard = self.guard;
+
+        Box::pin(async move {
+            if let Err(e) = guard.authorize(&req).await {
+                return Ok(req.error_response(e).map_into_right_body());
+            }
+            Ok(service.call(req).await?.map_into_left_body())
+        })
+    }
+}
@@ -0,0 +1,214 @@
+extern crate redis;
+use redis::aio::ConnectionManager;
+use std::env;
+use std::time::Duration;
+
+use crate::database::Database;
+use crate::error::AppError;
+use crate::utils::{
+    credentials_hashing,
+    email::{Email, Token},
+    refresh_token::RefreshToken,
+};
+
+use super::audit_log::{AuditAction, AuditContext, AuditLog, AuditTarget};
+use super::user::User;
+
+const DEFAULT_GRACE_PERIOD_DAYS: i64 = 30;
+const SWEEP_INTERVAL_SECS: u64 = 60 * 60;
+
+// Deleting an account only schedules it, the personal data is anonymized after a grace period
+// (`ACCOUNT_DELETION_GRACE_DAYS`), the row stays as a tombstone so the orders are kept
+pub struct AccountDeletion;
+
+impl AccountDeletion {
+    fn grace_period() -> chrono::Duration {
+        let days = env::var("ACCOUNT_DELETION_GRACE_DAYS")
+            .ok()
+            .and_then(|days| days.parse().ok())
+            .unwrap_or(DEFAULT_GRACE_PERIOD_DAYS);
+        chrono::Duration::days(days)
+    }
+
+    // The password is asked again, a stolen session alone can't delete the account
+    pub async fn schedule(
+        db: &Database,
+        redis_con: &mut ConnectionManager,
+        user_id: i32,
+        password: &str,
+        audit: &AuditContext,
+    ) -> Result<chrono::NaiveDateTime, AppError> {
+        let user = sqlx::query!(r#"SELECT email, password FROM users WHERE id = ?"#, user_id)
+            .fetch_optional(&db.pool)
+            .await?;
+
+        let Some(user) = user else {
+            return Err(AppError::NotFound("user_not_found"));
+        };
+        if !credentials_hashing::verify_password(password, &user.password) {
+            return Err(AppError::Unauthorized("invalid_password"));
+        }
+
+        let delete_at = chrono::Utc::now().naive_utc() + Self::grace_period();
+        sqlx::query!(
+            r#"UPDATE users SET deletion_scheduled_at = ? WHERE id = ?"#,
+            delete_at,
+            user_id
+        )
+        .execute(&db.pool)
+        .await?;
+
+        // Logging in again is how the deletion is cancelled, so every session ends here
+        RefreshToken::revoke_all(redis_con, user_id as usize).await?;
+
+        let locale = User::get_locale(db, user_id).await?;
+        let date = delete_at.format("%Y-%m-%d").to_string();
+        Email::send_account_deletion_scheduled(redis_con, locale, &user.email, &date).await?;
+
+        let changes = Some(serde_json::json!({ "delete_at": delete_at }));
+        let target = AuditTarget::User(user_id);
+        AuditLog::record(
+            db,
+            audit,
+            AuditAction::AccountDeletionScheduled,
+            target,
+            changes,
+        )
+        .await;
+        Ok(delete_at)
+    }
+
+    // Called on every login, returns whether a pending deletion was cancelled
+    pub async fn cancel(
+        db: &Database,
+        user_id: i32,
+        audit: &AuditContext,
+    ) -> Result<bool, AppError> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE users SET deletion_scheduled_at = NULL
+            WHERE id = ? AND deletion_scheduled_at IS NOT NULL AND anonymized_at IS NULL
+            "#,
+            user_id
+        )
+        .execute(&db.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        let target = AuditTarget::User(user_id);
+        AuditLog::record(
+            db,
+            audit,
+            AuditAction::AccountDeletionCancelled,
+            target,
+            None,
+        )
+        .await;
+        Ok(true)
+    }
+
+    // Anonymize every account whose grace period is over, an account that fails is tried again at
+    // the next sweep without holding up the others
+    pub async fn anonymize_due(db: &Database) -> Result<usize, AppError> {
+        let now = chrono::Utc::now().naive_utc();
+        let user_ids = sqlx::query_scalar!(
+            r#"
+            SELECT id FROM users
+            WHERE deletion_scheduled_at <= ? AND anonymized_at IS NULL
+            "#,
+            now
+        )
+        .fetch_all(&db.pool)
+        .await?;
+
+        let mut anonymized = 0;
+        for user_id in user_ids {
+            match Self::anonymize(db, user_id, now).await {
+                Ok(true) => anonymized += 1,
+                Ok(false) => {}
+                Err(e) => log::error!("Fióktörlési hiba ({user_id}): {:?}", e),
+            }
+        }
+        Ok(anonymized)
+    }
+
+    // Wipe the personal fields but keep the row, the orders stay linked to it
+    async fn anonymize(
+        db: &Database,
+        user_id: i32,
+        now: chrono::NaiveDateTime,
+    ) -> Result<bool, AppError> {
+        let mut tx = db.pool.begin().await?;
+
+        // The login that cancels the deletion may have happened since the accounts were listed
+        let result = sqlx::query!(
+            r#"
+            UPDATE users
+            SET username = ?, email = ?, password = ?, email_verified = FALSE,
+                totp_secret = NULL, totp_enabled = FALSE, totp_required = FALSE,
+                role = 'customer', disabled = TRUE, deletion_scheduled_at = NULL, anonymized_at = ?
+            WHERE id = ? AND deletion_scheduled_at <= ? AND anonymized_at IS NULL
+            "#,
+            format!("deleted-{user_id}"),
+            format!("deleted-{user_id}@deleted.invalid"),
+            // A valid hash of a password nobody knows
+            credentials_hashing::hash_password(&Token::generate_reset_token()),
+            now,
+            user_id,
+            now
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        sqlx::query!(
+            r#"
+            UPDATE user_info
+            SET first_name = '', last_name = '', phone_number = '', billing_address = '',
+                city = '', state_province = '', postal_code = ''
+            WHERE user_id = ?
+            "#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r#"DELETE FROM user_recovery_codes WHERE user_id = ?"#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(r#"DELETE FROM user_cart WHERE user_id = ?"#, user_id)
+            .execute(&mut *tx)
+            .await?;
+        AuditLog::anonymize_user(&mut tx, user_id).await?;
+        tx.commit().await?;
+
+        let mut redis_con = db.redis.clone();
+        RefreshToken::revoke_all(&mut redis_con, user_id as usize).await?;
+
+        let audit = AuditContext::default();
+        let target = AuditTarget::User(user_id);
+        AuditLog::record(db, &audit, AuditAction::AccountDeleted, target, None).await;
+        Ok(true)
+    }
+
+    // Start the background task anonymizing the accounts, once an hour
+    pub fn spawn_worker(db: Database) {
+        actix_web::rt::spawn(async move {
+            let mut interval =
+                actix_web::rt::time::interval(Duration::from_secs(SWEEP_INTERVAL_SECS));
+            loop {
+                interval.tick().await;
+                if let Err(e) = Self::anonymize_due(&db).await {
+                    log::error!("Fióktörlési hiba: {:?}", e);
+                }
+            }
+        });
+    }
+}
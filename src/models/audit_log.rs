@@ -0,0 +1,229 @@
+use actix_web::HttpRequest;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::MySqlConnection;
+
+use crate::database::Database;
+use crate::error::AppError;
+use crate::utils::refresh_token::SessionInfo;
+
+// Widths of the `ip` and `user_agent` columns
+const IP_MAX_CHARS: usize = 45;
+const USER_AGENT_MAX_CHARS: usize = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum AuditAction {
+    Login,
+    LoginFailed,
+    PasswordChanged,
+    PasswordReset,
+    PasswordResetRequested,
+    EmailChanged,
+    EmailChangeReverted,
+    UsernameChanged,
+    AccountDeletionScheduled,
+    AccountDeletionCancelled,
+    AccountDeleted,
+    DataExportRequested,
+    AccountDisabled,
+    AccountEnabled,
+    RoleChanged,
+    TwoFactorRequirementChanged,
+    BookCreated,
+    OrderStatusChanged,
+}
+
+// What the action was done to
+pub enum AuditTarget {
+    None,
+    User(i32),
+    Book(i32),
+    Order(u64),
+}
+
+impl AuditTarget {
+    fn parts(&self) -> (Option<&'static str>, Option<String>) {
+        match self {
+            AuditTarget::None => (None, None),
+            AuditTarget::User(id) => (Some("user"), Some(id.to_string())),
+            AuditTarget::Book(id) => (Some("book"), Some(id.to_string())),
+            AuditTarget::Order(id) => (Some("order"), Some(id.to_string())),
+        }
+    }
+}
+
+// Who did something and from where, the actor is filled in once it is known
+#[derive(Debug, Clone, Default)]
+pub struct AuditContext {
+    pub actor_id: Option<i32>,
+    pub ip: String,
+    pub user_agent: String,
+}
+
+impl AuditContext {
+    // Cut to the column widths, an overlong header would make the insert fail and the entry
+    // would be lost
+    pub fn from_request(req: &HttpRequest) -> Self {
+        let info = SessionInfo::from_request(req);
+        Self {
+            actor_id: None,
+            ip: info.ip.chars().take(IP_MAX_CHARS).collect(),
+            user_agent: info.user_agent.chars().take(USER_AGENT_MAX_CHARS).collect(),
+        }
+    }
+
+    pub fn with_actor(&self, actor_id: i32) -> Self {
+        Self {
+            actor_id: Some(actor_id),
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditLogEntry {
+    pub id: i64,
+    pub actor_id: Option<i32>,
+    pub action: String,
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    pub ip: String,
+    pub user_agent: String,
+    pub changes: Option<serde_json::Value>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditLogFilter {
+    pub actor_id: Option<i32>,
+    pub action: Option<AuditAction>,
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    pub from: Option<chrono::NaiveDateTime>,
+    pub to: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditLogPage {
+    pub entries: Vec<AuditLogEntry>,
+    pub page: u32,
+    pub per_page: u32,
+}
+
+// The log is append-only, there is deliberately no way to change or delete an entry. The only
+// exception is wiping the personal data of an anonymized account
+pub struct AuditLog;
+
+impl AuditLog {
+    // A failed write is only logged, the action itself has already happened
+    pub async fn record(
+        db: &Database,
+        context: &AuditContext,
+        action: AuditAction,
+        target: AuditTarget,
+        changes: Option<serde_json::Value>,
+    ) {
+        let (target_type, target_id) = target.parts();
+        let result = sqlx::query!(
+            r#"
+            INSERT INTO audit_log(actor_id, action, target_type, target_id, ip, user_agent, changes)
+            VALUES(?, ?, ?, ?, ?, ?, ?)
+            "#,
+            context.actor_id,
+            action,
+            target_type,
+            target_id,
+            context.ip,
+            context.user_agent,
+            changes
+        )
+        .execute(&db.pool)
+        .await;
+
+        if let Err(e) = result {
+            log::error!("Audit napló hiba: {:?}", e);
+        }
+    }
+
+    // Wipe the personal data of a user from the log, the entries themselves are kept. The changes
+    // of the actions done to the user hold their old usernames and e-mail addresses, the actions
+    // done by the user only keep what was changed on others
+    pub async fn anonymize_user(con: &mut MySqlConnection, user_id: i32) -> Result<(), AppError> {
+        sqlx::query!(
+            r#"
+            UPDATE audit_log SET ip = '', user_agent = '', changes = NULL
+            WHERE target_type = 'user' AND target_id = ?
+            "#,
+            user_id.to_string()
+        )
+        .execute(&mut *con)
+        .await?;
+        sqlx::query!(
+            r#"UPDATE audit_log SET ip = '', user_agent = '' WHERE actor_id = ?"#,
+            user_id
+        )
+        .execute(&mut *con)
+        .await?;
+        Ok(())
+    }
+
+    // The diff of a single field
+    pub fn change(
+        field: &str,
+        from: impl Serialize,
+        to: impl Serialize,
+    ) -> Option<serde_json::Value> {
+        Some(json!({ field: { "from": from, "to": to } }))
+    }
+
+    // Newest entries first, every filter is optional
+    pub async fn query(
+        db: &Database,
+        filter: &AuditLogFilter,
+        page: u32,
+        per_page: u32,
+    ) -> Result<AuditLogPage, AppError> {
+        // In u64, a huge page number would overflow a u32
+        let offset = u64::from(page.saturating_sub(1)) * u64::from(per_page);
+        let entries = sqlx::query_as!(
+            AuditLogEntry,
+            r#"
+            SELECT id, actor_id, action, target_type, target_id, ip, user_agent,
+                changes AS `changes: serde_json::Value`, created_at
+            FROM audit_log
+            WHERE (? IS NULL OR actor_id = ?)
+                AND (? IS NULL OR action = ?)
+                AND (? IS NULL OR target_type = ?)
+                AND (? IS NULL OR target_id = ?)
+                AND (? IS NULL OR created_at >= ?)
+                AND (? IS NULL OR created_at <= ?)
+            ORDER BY id DESC
+            LIMIT ? OFFSET ?
+            "#,
+            filter.actor_id,
+            filter.actor_id,
+            filter.action,
+            filter.action,
+            filter.target_type,
+            filter.target_type,
+            filter.target_id,
+            filter.target_id,
+            filter.from,
+            filter.from,
+            filter.to,
+            filter.to,
+            per_page,
+            offset
+        )
+        .fetch_all(&db.pool)
+        .await?;
+
+        Ok(AuditLogPage {
+            entries,
+            page,
+            per_page,
+        })
+    }
+}
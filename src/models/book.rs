@@ -1,23 +1,37 @@
 use crate::database::Database;
+use crate::error::AppError;
+use crate::models::audit_log::{AuditAction, AuditContext, AuditLog, AuditTarget};
+use crate::utils::validation::validate_isbn;
 
 use serde::{Deserialize, Serialize};
 use sqlx::prelude::FromRow;
-use std::error::Error;
+use validator::Validate;
 
-#[derive(Debug, Serialize, Deserialize, FromRow)]
+#[derive(Debug, Serialize, Deserialize, FromRow, Validate)]
 pub struct Book {
     pub id: Option<i32>,
+    #[validate(length(min = 1, max = 50))]
     pub title: String,
+    #[validate(length(min = 1, max = 50))]
     pub author: String,
+    #[validate(range(min = 1, max = 99999))]
     pub price: i32,
+    #[validate(length(min = 1, max = 1000))]
     pub description: String,
+    #[validate(required, url, length(max = 255))]
     pub image_src: Option<String>,
+    #[validate(length(min = 1, max = 255))]
     pub published_date: String,
+    #[validate(custom(function = validate_isbn))]
     pub isbn: String,
+    // Most copies a single cart may hold, unset means the default limit
+    #[serde(default)]
+    #[validate(range(min = 1, max = 999))]
+    pub max_cart_quantity: Option<i32>,
 }
 
 impl Book {
-    pub async fn create(db: &Database, book: Book) -> Result<(), Box<dyn Error>> {
+    pub async fn create(db: &Database, book: Book, audit: &AuditContext) -> Result<(), AppError> {
         // Check if any required fields are null or empty
         if book.title.is_empty()
             || book.author.is_empty()
@@ -26,10 +40,7 @@ impl Book {
             || book.published_date.is_empty()
             || book.isbn.is_empty()
         {
-            return Err(
-                "Minden mező (title, author, price, description, imageSrc, publishedDate, isbn) kitöltése kötelező"
-                    .into(),
-            );
+            return Err(AppError::BadRequest("missing_book_fields"));
         }
 
         // Check if the book title or isbn already exists
@@ -42,26 +53,30 @@ impl Book {
         .await?;
 
         if existing_book.is_some() {
-            return Err("A könyv már létezik".into());
+            return Err(AppError::Conflict("book_exists"));
         }
 
-        sqlx::query!(
-            r#"INSERT INTO books(title, author, price, description, image_src, published_date, isbn) VALUES(?, ?, ?, ?, ?, ?, ?)"#,
+        let result = sqlx::query!(
+            r#"INSERT INTO books(title, author, price, description, image_src, published_date, isbn, max_cart_quantity) VALUES(?, ?, ?, ?, ?, ?, ?, ?)"#,
             book.title,
             book.author,
             book.price,
             book.description,
             book.image_src.clone().unwrap_or("".to_string()),
             book.published_date,
-            book.isbn
+            book.isbn,
+            book.max_cart_quantity
         )
         .execute(&db.pool)
         .await?;
 
+        let changes = Some(serde_json::json!({ "title": book.title, "isbn": book.isbn }));
+        let target = AuditTarget::Book(result.last_insert_id() as i32);
+        AuditLog::record(db, audit, AuditAction::BookCreated, target, changes).await;
         Ok(())
     }
 
-    pub async fn get_all(db: &Database) -> Result<Vec<Book>, Box<dyn Error>> {
+    pub async fn get_all(db: &Database) -> Result<Vec<Book>, AppError> {
         let books = sqlx::query_as!(Book, r#"SELECT * FROM books"#)
             .fetch_all(&db.pool)
             .await?;
@@ -69,7 +84,7 @@ impl Book {
         Ok(books)
     }
 
-    pub async fn get_best_sellers(db: &Database) -> Result<Vec<Book>, Box<dyn Error>> {
+    pub async fn get_best_sellers(db: &Database) -> Result<Vec<Book>, AppError> {
         let books = sqlx::query_as!(Book, r#"SELECT * FROM books LIMIT 10, 4"#)
             .fetch_all(&db.pool)
             .await?;
@@ -77,15 +92,18 @@ impl Book {
         Ok(books)
     }
 
-    pub async fn get_by_id(db: &Database, book_id: i32) -> Result<Book, Box<dyn Error>> {
+    pub async fn get_by_id(db: &Database, book_id: i32) -> Result<Book, AppError> {
         let book = sqlx::query_as!(Book, r#"SELECT * FROM books WHERE id = ?"#, book_id)
-            .fetch_one(&db.pool)
+            .fetch_optional(&db.pool)
             .await?;
 
-        Ok(book)
+        match book {
+            Some(book) => Ok(book),
+            None => Err(AppError::NotFound("book_not_found")),
+        }
     }
 
-    pub async fn filter_by(db: &Database, query: &str) -> Result<Vec<Book>, Box<dyn Error>> {
+    pub async fn filter_by(db: &Database, query: &str) -> Result<Vec<Book>, AppError> {
         let query_with_whitespace = format!("%{}%", query);
         let query_without_whitespace = format!("%{}%", remove_whitespace(query));
         let books = sqlx::query_as!(
@@ -1,10 +1,15 @@
 use crate::database::Database;
+use crate::error::AppError;
 
 use serde::{Deserialize, Serialize};
-use std::error::Error;
+use std::collections::HashSet;
+use std::env;
+use validator::Validate;
 
 use super::user::User;
 
+const DEFAULT_MAX_ITEM_QUANTITY: i32 = 10;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Cart {
     pub id: Option<i32>,
@@ -22,10 +27,56 @@ pub struct CartBook {
     pub quantity: i32,
 }
 
+// The new quantity of a book in the cart, 0 removes it
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct CartItemChange {
+    #[validate(range(min = 1))]
+    pub book_id: i32,
+    #[validate(range(min = 0, max = 999))]
+    pub quantity: i32,
+}
+
 impl Cart {
-    pub async fn create(db: &Database, user_id: i32) -> Result<(), Box<dyn Error>> {
+    // Most copies of the book a cart may hold, `CART_MAX_ITEM_QUANTITY` unless the book sets its own
+    pub(crate) async fn max_quantity(db: &Database, book_id: i32) -> Result<i32, AppError> {
+        let book = sqlx::query!(
+            r#"SELECT max_cart_quantity FROM books WHERE id = ?"#,
+            book_id
+        )
+        .fetch_optional(&db.pool)
+        .await?;
+
+        let Some(book) = book else {
+            return Err(AppError::NotFound("book_not_found"));
+        };
+        Ok(book.max_cart_quantity.unwrap_or_else(|| {
+            env::var("CART_MAX_ITEM_QUANTITY")
+                .ok()
+                .and_then(|max| max.parse().ok())
+                .unwrap_or(DEFAULT_MAX_ITEM_QUANTITY)
+        }))
+    }
+
+    // Fail if the quantity is over the limit of the book, the limit is sent in the details
+    pub(crate) async fn check_quantity(
+        db: &Database,
+        book_id: i32,
+        quantity: i32,
+    ) -> Result<(), AppError> {
+        let max = Self::max_quantity(db, book_id).await?;
+        if quantity > max {
+            let details = serde_json::json!({ "book_id": book_id, "max": max });
+            return Err(AppError::Unprocessable(
+                "cart_quantity_too_large",
+                Some(details),
+            ));
+        }
+        Ok(())
+    }
+
+    pub async fn create(db: &Database, user_id: i32) -> Result<(), AppError> {
         if !User::is_user_exists(db, user_id).await? {
-            return Err("A felhasználó nem található".into());
+            return Err(AppError::NotFound("user_not_found"));
         }
 
         // Check if user has a cart
@@ -34,7 +85,7 @@ impl Cart {
             .await?;
 
         if cart.is_some() {
-            return Err("A felhasználónak már van kosara".into());
+            return Err(AppError::Conflict("cart_exists"));
         }
 
         // Create a new cart
@@ -46,7 +97,7 @@ impl Cart {
     }
 
     // Get user's cart
-    pub async fn get_cart(db: &Database, user_id: i32) -> Result<Cart, Box<dyn Error>> {
+    pub async fn get_cart(db: &Database, user_id: i32) -> Result<Cart, AppError> {
         let cart = sqlx::query!(r#"SELECT * FROM user_cart WHERE user_id = ?"#, user_id)
             .fetch_optional(&db.pool)
             .await?;
@@ -81,7 +132,7 @@ impl Cart {
         }
     }
 
-    pub async fn delete_cart(db: &Database, user_id: i32) -> Result<(), Box<dyn Error>> {
+    pub async fn delete_cart(db: &Database, user_id: i32) -> Result<(), AppError> {
         sqlx::query!(r#"DELETE FROM user_cart WHERE user_id = ?"#, user_id)
             .execute(&db.pool)
             .await?;
@@ -92,29 +143,31 @@ impl Cart {
         db: &Database,
         user_id: i32,
         book_id: i32,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<(), AppError> {
         // Check if user has a cart
         let cart = sqlx::query!(r#"SELECT * FROM user_cart WHERE user_id = ?"#, user_id)
             .fetch_one(&db.pool)
             .await?;
 
-        // Check if book exists
-        if sqlx::query!(r#"SELECT * FROM books WHERE id = ?"#, book_id)
-            .fetch_optional(&db.pool)
-            .await?
-            .is_none()
-        {
-            return Err("A könyv nem létezik".into());
-        }
+        // Check if book exists and one more copy still fits
+        let quantity = sqlx::query_scalar!(
+            r#"SELECT quantity FROM cart_items WHERE cart_id = ? AND book_id = ?"#,
+            cart.id,
+            book_id
+        )
+        .fetch_optional(&db.pool)
+        .await?;
+        Self::check_quantity(db, book_id, quantity.unwrap_or(0) + 1).await?;
 
         // Upsert the cart item
         sqlx::query!(
             r#"
-            INSERT INTO cart_items (cart_id, book_id, quantity)
-            VALUES (?, ?, 1)
+            INSERT INTO cart_items (cart_id, book_id, quantity, price_at_add)
+            VALUES (?, ?, 1, (SELECT price FROM books WHERE id = ?))
             ON DUPLICATE KEY UPDATE quantity = quantity + 1
             "#,
             cart.id,
+            book_id,
             book_id
         )
         .execute(&db.pool)
@@ -127,7 +180,7 @@ impl Cart {
         db: &Database,
         user_id: i32,
         book_id: i32,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<(), AppError> {
         let cart = sqlx::query!(r#"SELECT * FROM user_cart WHERE user_id = ?"#, user_id)
             .fetch_optional(&db.pool)
             .await?;
@@ -162,7 +215,76 @@ impl Cart {
             .await?;
             Ok(())
         } else {
-            Err("A felhasználónak nincs kosara".into())
+            Err(AppError::NotFound("cart_not_found"))
+        }
+    }
+
+    // Set the quantities of several books at once, either every change is applied or none
+    pub async fn apply_changes(
+        db: &Database,
+        user_id: i32,
+        changes: &[CartItemChange],
+    ) -> Result<(), AppError> {
+        let mut book_ids = HashSet::new();
+        for change in changes.iter() {
+            if !book_ids.insert(change.book_id) {
+                return Err(AppError::BadRequest("duplicate_cart_item"));
+            }
+            if change.quantity > 0 {
+                Self::check_quantity(db, change.book_id, change.quantity).await?;
+            }
+        }
+
+        // Creates the cart of the user if there is none yet
+        let cart_id = Self::get_cart(db, user_id).await?.id;
+
+        let mut tx = db.pool.begin().await?;
+        for change in changes.iter() {
+            if change.quantity == 0 {
+                sqlx::query!(
+                    r#"DELETE FROM cart_items WHERE cart_id = ? AND book_id = ?"#,
+                    cart_id,
+                    change.book_id
+                )
+                .execute(&mut *tx)
+                .await?;
+            } else {
+                sqlx::query!(
+                    r#"
+                    INSERT INTO cart_items (cart_id, book_id, quantity, price_at_add)
+                    VALUES (?, ?, ?, (SELECT price FROM books WHERE id = ?))
+                    ON DUPLICATE KEY UPDATE quantity = VALUES(quantity)
+                    "#,
+                    cart_id,
+                    change.book_id,
+                    change.quantity,
+                    change.book_id
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
         }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn remove_book(db: &Database, user_id: i32, book_id: i32) -> Result<(), AppError> {
+        let result = sqlx::query!(
+            r#"
+            DELETE cart_items FROM cart_items
+            JOIN user_cart ON cart_items.cart_id = user_cart.id
+            WHERE user_cart.user_id = ? AND cart_items.book_id = ?
+            "#,
+            user_id,
+            book_id
+        )
+        .execute(&db.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(AppError::NotFound("cart_item_not_found"));
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,279 @@
+use serde::Serialize;
+use std::env;
+use std::sync::OnceLock;
+
+use crate::database::Database;
+use crate::error::AppError;
+
+const DEFAULT_VAT_PERCENT: i64 = 5;
+const DEFAULT_SHIPPING_FEE: i64 = 1490;
+const DEFAULT_FREE_SHIPPING_FROM: i64 = 15000;
+const MAX_AMOUNT: i64 = i32::MAX as i64;
+
+// How the total of an order is made up, the book prices are gross so the VAT is the part of the
+// total already included in them
+//  - `CART_VAT_PERCENT` is the VAT rate
+//  - `CART_SHIPPING_FEE` is charged below `CART_FREE_SHIPPING_FROM`
+//  - `CART_DISCOUNT_PERCENT` is taken off subtotals of at least `CART_DISCOUNT_FROM`, 0 turns it off
+// The amounts are in i64, a full cart of expensive books doesn't fit in an i32
+pub struct PricingRules {
+    vat_percent: i64,
+    shipping_fee: i64,
+    free_shipping_from: i64,
+    discount_percent: i64,
+    discount_from: i64,
+}
+
+impl PricingRules {
+    fn from_env() -> Result<Self, String> {
+        fn var(name: &str, default: i64, max: i64) -> Result<i64, String> {
+            let Ok(value) = env::var(name) else {
+                return Ok(default);
+            };
+            value
+                .trim()
+                .parse()
+                .ok()
+                .filter(|value| (0..=max).contains(value))
+                .ok_or(format!("Invalid {name}: {value}"))
+        }
+
+        Ok(Self {
+            vat_percent: var("CART_VAT_PERCENT", DEFAULT_VAT_PERCENT, 100)?,
+            shipping_fee: var("CART_SHIPPING_FEE", DEFAULT_SHIPPING_FEE, MAX_AMOUNT)?,
+            free_shipping_from: var(
+                "CART_FREE_SHIPPING_FROM",
+                DEFAULT_FREE_SHIPPING_FROM,
+                MAX_AMOUNT,
+            )?,
+            discount_percent: var("CART_DISCOUNT_PERCENT", 0, 100)?,
+            discount_from: var("CART_DISCOUNT_FROM", 0, MAX_AMOUNT)?,
+        })
+    }
+
+    // Read once, `Server::run` loads them at startup so a bad value stops the server
+    pub fn get() -> Result<&'static Self, String> {
+        static RULES: OnceLock<Result<PricingRules, String>> = OnceLock::new();
+        RULES
+            .get_or_init(Self::from_env)
+            .as_ref()
+            .map_err(String::clone)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CartPreviewLine {
+    pub book_id: i32,
+    pub title: String,
+    pub author: String,
+    pub isbn: String,
+    pub quantity: i32,
+    pub unit_price: i32,
+    // The price the customer saw when putting the book in the cart
+    pub price_at_add: i32,
+    pub price_changed: bool,
+    pub line_total: i64,
+}
+
+// What the cart would cost if it was bought now
+#[derive(Debug, Serialize)]
+pub struct CartPreview {
+    pub lines: Vec<CartPreviewLine>,
+    pub subtotal: i64,
+    pub discount: i64,
+    pub shipping: i64,
+    pub vat: i64,
+    pub grand_total: i64,
+    // The customer has to confirm the new prices before buying the cart
+    pub prices_changed: bool,
+}
+
+impl CartPreview {
+    pub async fn build(db: &Database, user_id: i32) -> Result<CartPreview, AppError> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT book.id, book.title, book.author, book.isbn, book.price, cart_items.quantity, cart_items.price_at_add
+            FROM books book
+            JOIN cart_items ON book.id = cart_items.book_id
+            JOIN user_cart ON cart_items.cart_id = user_cart.id
+            WHERE user_cart.user_id = ?
+            ORDER BY book.id
+            "#,
+            user_id
+        )
+        .fetch_all(&db.pool)
+        .await?;
+
+        let lines: Vec<CartPreviewLine> = rows
+            .into_iter()
+            .map(|row| {
+                // Items added before the prices were recorded are taken as unchanged
+                let price_at_add = row.price_at_add.unwrap_or(row.price);
+                CartPreviewLine {
+                    book_id: row.id,
+                    title: row.title,
+                    author: row.author,
+                    isbn: row.isbn,
+                    quantity: row.quantity,
+                    unit_price: row.price,
+                    price_at_add,
+                    price_changed: price_at_add != row.price,
+                    line_total: i64::from(row.price) * i64::from(row.quantity),
+                }
+            })
+            .collect();
+
+        let rules = PricingRules::get().map_err(AppError::Internal)?;
+        Ok(Self::from_lines(lines, rules))
+    }
+
+    // The discount is taken before deciding on the shipping fee, the VAT is rounded to the forint
+    fn from_lines(lines: Vec<CartPreviewLine>, rules: &PricingRules) -> CartPreview {
+        let subtotal: i64 = lines.iter().map(|line| line.line_total).sum();
+        let discount = if rules.discount_percent > 0 && subtotal >= rules.discount_from {
+            subtotal * rules.discount_percent / 100
+        } else {
+            0
+        };
+        let shipping = if lines.is_empty() || subtotal - discount >= rules.free_shipping_from {
+            0
+        } else {
+            rules.shipping_fee
+        };
+        let grand_total = subtotal - discount + shipping;
+        let vat = (grand_total * rules.vat_percent + (100 + rules.vat_percent) / 2)
+            / (100 + rules.vat_percent);
+
+        CartPreview {
+            prices_changed: lines.iter().any(|line| line.price_changed),
+            lines,
+            subtotal,
+            discount,
+            shipping,
+            vat,
+            grand_total,
+        }
+    }
+
+    // The customer accepted the current prices, the changed lines aren't flagged anymore
+    pub async fn confirm_prices(db: &Database, user_id: i32) -> Result<(), AppError> {
+        sqlx::query!(
+            r#"
+            UPDATE cart_items
+            JOIN user_cart ON cart_items.cart_id = user_cart.id
+            JOIN books ON cart_items.book_id = books.id
+            SET cart_items.price_at_add = books.price
+            WHERE user_cart.user_id = ?
+            "#,
+            user_id
+        )
+        .execute(&db.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: PricingRules = PricingRules {
+        vat_percent: DEFAULT_VAT_PERCENT,
+        shipping_fee: DEFAULT_SHIPPING_FEE,
+        free_shipping_from: DEFAULT_FREE_SHIPPING_FROM,
+        discount_percent: 10,
+        discount_from: 20000,
+    };
+
+    fn line(book_id: i32, quantity: i32, unit_price: i32, price_at_add: i32) -> CartPreviewLine {
+        CartPreviewLine {
+            book_id,
+            title: format!("Könyv {book_id}"),
+            author: "Szerző".to_string(),
+            isbn: "978-0-306-40615-7".to_string(),
+            quantity,
+            unit_price,
+            price_at_add,
+            price_changed: price_at_add != unit_price,
+            line_total: i64::from(unit_price) * i64::from(quantity),
+        }
+    }
+
+    fn totals(preview: &CartPreview) -> (i64, i64, i64, i64, i64) {
+        (
+            preview.subtotal,
+            preview.discount,
+            preview.shipping,
+            preview.vat,
+            preview.grand_total,
+        )
+    }
+
+    #[test]
+    fn empty_cart_costs_nothing() {
+        let preview = CartPreview::from_lines(Vec::new(), &RULES);
+        assert_eq!(totals(&preview), (0, 0, 0, 0, 0));
+        assert!(!preview.prices_changed);
+    }
+
+    #[test]
+    fn shipping_is_charged_below_the_free_shipping_limit() {
+        let preview = CartPreview::from_lines(vec![line(1, 2, 3990, 3990)], &RULES);
+        // 7980 + 1490 = 9470, of which 5% VAT is 450.95
+        assert_eq!(totals(&preview), (7980, 0, 1490, 451, 9470));
+    }
+
+    #[test]
+    fn free_shipping_from_the_limit() {
+        let preview = CartPreview::from_lines(vec![line(1, 3, 5000, 5000)], &RULES);
+        assert_eq!(totals(&preview), (15000, 0, 0, 714, 15000));
+    }
+
+    #[test]
+    fn discount_can_bring_the_total_under_the_free_shipping_limit() {
+        let rules = PricingRules {
+            discount_from: 15000,
+            ..RULES
+        };
+        let preview = CartPreview::from_lines(vec![line(1, 1, 16000, 16000)], &rules);
+        assert_eq!(totals(&preview), (16000, 1600, 1490, 757, 15890));
+
+        let preview = CartPreview::from_lines(vec![line(1, 1, 16000, 16000)], &RULES);
+        assert_eq!(preview.discount, 0);
+        assert_eq!(preview.shipping, 0);
+    }
+
+    #[test]
+    fn full_cart_of_the_most_expensive_books_does_not_overflow() {
+        let lines = (1..=50).map(|id| line(id, 999, 99_999, 99_999)).collect();
+        let preview = CartPreview::from_lines(lines, &RULES);
+
+        let subtotal = 50 * 999 * 99_999;
+        let discount = subtotal / 10;
+        assert_eq!(preview.subtotal, subtotal);
+        assert_eq!(preview.grand_total, subtotal - discount);
+        assert_eq!(preview.vat, ((subtotal - discount) * 5 + 52) / 105);
+        assert!(preview.grand_total > i32::MAX as i64);
+    }
+
+    #[test]
+    fn unparsable_pricing_values_are_rejected() {
+        env::set_var("CART_VAT_PERCENT", "27%");
+        let result = PricingRules::from_env();
+        env::remove_var("CART_VAT_PERCENT");
+        assert_eq!(
+            result.err().as_deref(),
+            Some("Invalid CART_VAT_PERCENT: 27%")
+        );
+    }
+
+    #[test]
+    fn changed_prices_are_flagged() {
+        let preview =
+            CartPreview::from_lines(vec![line(1, 1, 3990, 3990), line(2, 1, 4490, 3990)], &RULES);
+        assert!(preview.prices_changed);
+        assert!(!preview.lines[0].price_changed);
+        assert!(preview.lines[1].price_changed);
+        assert_eq!(preview.subtotal, 8480);
+    }
+}
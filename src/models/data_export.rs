@@ -0,0 +1,300 @@
+extern crate redis;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use std::io::{Cursor, Write};
+use zip::write::SimpleFileOptions;
+
+use crate::database::Database;
+use crate::error::AppError;
+use crate::utils::{
+    email::{Email, Token},
+    export_link::ExportLinks,
+    redis::Redis,
+};
+
+use super::audit_log::{AuditAction, AuditContext, AuditLog, AuditTarget};
+use super::role::Role;
+use super::user::User;
+
+const EXPORT_TTL_SECS: i64 = 60 * 60 * 24;
+const EXPORT_COOLDOWN_SECS: u64 = 60 * 15;
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Json,
+    // A CSV file for every part of the export
+    Zip,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "application/json",
+            ExportFormat::Zip => "application/zip",
+        }
+    }
+
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "library-basement-export.json",
+            ExportFormat::Zip => "library-basement-export.zip",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct AccountData {
+    id: i32,
+    username: String,
+    email: String,
+    email_verified: bool,
+    role: Role,
+    locale: String,
+    two_factor_enabled: bool,
+    disabled: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct ProfileData {
+    first_name: String,
+    last_name: String,
+    phone_number: String,
+    billing_address: String,
+    city: String,
+    state_province: String,
+    postal_code: String,
+}
+
+#[derive(Debug, Serialize)]
+struct CartItemData {
+    book_id: i32,
+    title: String,
+    author: String,
+    price: i32,
+    isbn: String,
+    quantity: i32,
+}
+
+#[derive(Debug, Serialize)]
+struct OrderData {
+    id: i32,
+    status: String,
+    price: i32,
+    purchase_date: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Serialize)]
+struct OrderItemData {
+    order_id: i32,
+    book_id: i32,
+    title: String,
+    author: String,
+    price: i32,
+    isbn: String,
+    quantity: i32,
+}
+
+// Everything stored about a user, the password and 2FA secrets are left out on purpose
+#[derive(Debug, Serialize)]
+pub struct DataExport {
+    exported_at: chrono::NaiveDateTime,
+    account: AccountData,
+    profile: Option<ProfileData>,
+    cart: Vec<CartItemData>,
+    orders: Vec<OrderData>,
+    order_items: Vec<OrderItemData>,
+}
+
+impl DataExport {
+    // Start putting the export together, the download link is e-mailed once it's ready
+    pub async fn request(
+        db: &Database,
+        redis_con: &mut ConnectionManager,
+        user_id: i32,
+        format: ExportFormat,
+        links: ExportLinks,
+        audit: &AuditContext,
+    ) -> Result<(), AppError> {
+        let cooldown_key = format!("user-export-cooldown:{user_id}");
+        if let Some(remaining) =
+            Redis::try_start_cooldown(redis_con, &cooldown_key, EXPORT_COOLDOWN_SECS).await?
+        {
+            return Err(AppError::TooManyRequests("export_cooldown", remaining));
+        }
+
+        let target = AuditTarget::User(user_id);
+        AuditLog::record(db, audit, AuditAction::DataExportRequested, target, None).await;
+
+        let db = db.clone();
+        actix_web::rt::spawn(async move {
+            if let Err(e) = Self::generate(&db, user_id, format, &links).await {
+                log::error!("Adatexport hiba ({user_id}): {:?}", e);
+                // Let the user ask again right away instead of waiting for the cooldown
+                let mut redis_con = db.redis.clone();
+                let _ = redis_con.del::<_, ()>(&cooldown_key).await;
+            }
+        });
+        Ok(())
+    }
+
+    async fn generate(
+        db: &Database,
+        user_id: i32,
+        format: ExportFormat,
+        links: &ExportLinks,
+    ) -> Result<(), AppError> {
+        let export = Self::collect(db, user_id).await?;
+        let data = match format {
+            ExportFormat::Json => serde_json::to_vec_pretty(&export)?,
+            ExportFormat::Zip => export.to_zip()?,
+        };
+
+        // Stored under a random id, the link is what proves access
+        let export_id = Token::generate_reset_token();
+        let export_key = format!("user-export:{export_id}");
+        let mut redis_con = db.redis.clone();
+        redis::pipe()
+            .hset(&export_key, "format", serde_json::to_string(&format)?)
+            .hset(&export_key, "data", data)
+            .expire(&export_key, EXPORT_TTL_SECS)
+            .query_async::<()>(&mut redis_con)
+            .await?;
+
+        let expires = chrono::Utc::now().timestamp() + EXPORT_TTL_SECS;
+        let email = export.account.email;
+        let locale = User::get_locale(db, user_id).await?;
+        let url = links.url(&export_id, expires);
+        Email::send_data_export_link(&mut redis_con, locale, &email, &url).await
+    }
+
+    // The export behind a signed link
+    pub async fn download(
+        redis_con: &mut ConnectionManager,
+        links: &ExportLinks,
+        export_id: &str,
+        expires: i64,
+        signature: &str,
+    ) -> Result<(ExportFormat, Vec<u8>), AppError> {
+        if !links.verify(export_id, expires, signature) {
+            return Err(AppError::BadRequest("invalid_token"));
+        }
+
+        let export_key = format!("user-export:{export_id}");
+        let (format, data) = redis_con
+            .hget::<_, _, (Option<String>, Option<Vec<u8>>)>(&export_key, &["format", "data"])
+            .await?;
+        let (Some(format), Some(data)) = (format, data) else {
+            return Err(AppError::NotFound("export_not_found"));
+        };
+        Ok((serde_json::from_str(&format)?, data))
+    }
+
+    async fn collect(db: &Database, user_id: i32) -> Result<Self, AppError> {
+        let account = sqlx::query_as!(
+            AccountData,
+            r#"
+            SELECT id, username, email, email_verified AS `email_verified: bool`,
+                role AS `role: Role`, locale, totp_enabled AS `two_factor_enabled: bool`,
+                disabled AS `disabled: bool`
+            FROM users WHERE id = ?
+            "#,
+            user_id
+        )
+        .fetch_optional(&db.pool)
+        .await?;
+
+        let Some(account) = account else {
+            return Err(AppError::NotFound("user_not_found"));
+        };
+
+        let profile = sqlx::query_as!(
+            ProfileData,
+            r#"
+            SELECT first_name, last_name, phone_number, billing_address, city, state_province, postal_code
+            FROM user_info WHERE user_id = ?
+            "#,
+            user_id
+        )
+        .fetch_optional(&db.pool)
+        .await?;
+
+        let cart = sqlx::query_as!(
+            CartItemData,
+            r#"
+            SELECT book.id AS book_id, book.title, book.author, book.price, book.isbn, cart_items.quantity
+            FROM cart_items
+            JOIN user_cart ON cart_items.cart_id = user_cart.id
+            JOIN books book ON book.id = cart_items.book_id
+            WHERE user_cart.user_id = ?
+            "#,
+            user_id
+        )
+        .fetch_all(&db.pool)
+        .await?;
+
+        let orders = sqlx::query_as!(
+            OrderData,
+            r#"
+            SELECT id, status, price, purchase_date
+            FROM transaction_history WHERE user_id = ?
+            ORDER BY id
+            "#,
+            user_id
+        )
+        .fetch_all(&db.pool)
+        .await?;
+
+        let order_items = sqlx::query_as!(
+            OrderItemData,
+            r#"
+            SELECT tb.transaction_history_id AS order_id, b.id AS book_id, b.title, b.author, b.price, b.isbn, tb.quantity
+            FROM transaction_books tb
+            JOIN transaction_history th ON th.id = tb.transaction_history_id
+            JOIN books b ON b.id = tb.book_id
+            WHERE th.user_id = ?
+            ORDER BY tb.transaction_history_id
+            "#,
+            user_id
+        )
+        .fetch_all(&db.pool)
+        .await?;
+
+        Ok(Self {
+            exported_at: chrono::Utc::now().naive_utc(),
+            account,
+            profile,
+            cart,
+            orders,
+            order_items,
+        })
+    }
+
+    fn to_zip(&self) -> Result<Vec<u8>, AppError> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let files = [
+            ("account.csv", to_csv(std::slice::from_ref(&self.account))?),
+            ("profile.csv", to_csv(self.profile.as_slice())?),
+            ("cart.csv", to_csv(&self.cart)?),
+            ("orders.csv", to_csv(&self.orders)?),
+            ("order_items.csv", to_csv(&self.order_items)?),
+        ];
+        for (name, content) in files {
+            zip.start_file(name, SimpleFileOptions::default())?;
+            zip.write_all(&content)?;
+        }
+        Ok(zip.finish()?.into_inner())
+    }
+}
+
+fn to_csv<T: Serialize>(rows: &[T]) -> Result<Vec<u8>, AppError> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer
+        .into_inner()
+        .map_err(|e| AppError::Internal(e.to_string()))
+}
@@ -0,0 +1,212 @@
+extern crate redis;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::env;
+
+use crate::database::Database;
+use crate::error::AppError;
+
+use super::cart::{Cart, CartBook};
+
+const DEFAULT_TTL_DAYS: i64 = 7;
+
+// What happens to a book that is in both carts when they are merged, `GUEST_CART_MERGE_RULE`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CartMergeRule {
+    // The quantities are added up
+    Sum,
+    // The larger quantity is kept
+    Max,
+}
+
+impl CartMergeRule {
+    fn from_env() -> Self {
+        match env::var("GUEST_CART_MERGE_RULE").as_deref() {
+            Ok("max") => CartMergeRule::Max,
+            _ => CartMergeRule::Sum,
+        }
+    }
+}
+
+// The cart of a visitor, kept in Redis as book id -> quantity until `GUEST_CART_TTL_DAYS` pass
+// without a change. The price of each book when it was first added is kept next to it, so a
+// price change is flagged once the cart is merged at login
+#[derive(Debug, Serialize)]
+pub struct GuestCart {
+    pub books: Vec<CartBook>,
+}
+
+impl GuestCart {
+    fn key(cart_id: &str) -> String {
+        format!("guest-cart:{cart_id}")
+    }
+
+    fn prices_key(cart_id: &str) -> String {
+        format!("guest-cart-prices:{cart_id}")
+    }
+
+    fn ttl_secs() -> i64 {
+        let days = env::var("GUEST_CART_TTL_DAYS")
+            .ok()
+            .and_then(|days| days.parse().ok())
+            .unwrap_or(DEFAULT_TTL_DAYS);
+        days * 60 * 60 * 24
+    }
+
+    async fn get_items(
+        redis_con: &mut ConnectionManager,
+        cart_id: &str,
+    ) -> Result<HashMap<i32, i32>, AppError> {
+        Ok(redis_con
+            .hgetall::<_, HashMap<i32, i32>>(Self::key(cart_id))
+            .await?)
+    }
+
+    // An expired or never used cart is just empty
+    pub async fn get_cart(
+        db: &Database,
+        redis_con: &mut ConnectionManager,
+        cart_id: &str,
+    ) -> Result<GuestCart, AppError> {
+        let mut books = Vec::new();
+        for (book_id, quantity) in Self::get_items(redis_con, cart_id).await? {
+            let book = sqlx::query!(
+                r#"SELECT id, title, author, price, isbn FROM books WHERE id = ?"#,
+                book_id
+            )
+            .fetch_optional(&db.pool)
+            .await?;
+
+            // Books deleted since they were added are left out
+            if let Some(book) = book {
+                books.push(CartBook {
+                    id: Some(book.id),
+                    title: book.title,
+                    author: book.author,
+                    price: book.price,
+                    isbn: book.isbn,
+                    quantity,
+                });
+            }
+        }
+        books.sort_by_key(|book| book.id);
+
+        Ok(GuestCart { books })
+    }
+
+    pub async fn increment_book_quantity(
+        db: &Database,
+        redis_con: &mut ConnectionManager,
+        cart_id: &str,
+        book_id: i32,
+    ) -> Result<(), AppError> {
+        // Check if book exists and one more copy still fits
+        let key = Self::key(cart_id);
+        let quantity = redis_con
+            .hget::<_, _, Option<i32>>(&key, book_id)
+            .await?
+            .unwrap_or(0);
+        Cart::check_quantity(db, book_id, quantity + 1).await?;
+        let price = sqlx::query_scalar!(r#"SELECT price FROM books WHERE id = ?"#, book_id)
+            .fetch_one(&db.pool)
+            .await?;
+
+        let prices_key = Self::prices_key(cart_id);
+        redis::pipe()
+            .hincr(&key, book_id, 1)
+            .hset_nx(&prices_key, book_id, price)
+            .expire(&key, Self::ttl_secs())
+            .expire(&prices_key, Self::ttl_secs())
+            .query_async::<()>(redis_con)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn decrease_book_quantity(
+        redis_con: &mut ConnectionManager,
+        cart_id: &str,
+        book_id: i32,
+    ) -> Result<(), AppError> {
+        let key = Self::key(cart_id);
+        if !redis_con.hexists::<_, _, bool>(&key, book_id).await? {
+            return Err(AppError::NotFound("cart_not_found"));
+        }
+
+        // If quantity becomes 0, remove the item
+        let quantity = redis_con.hincr::<_, _, _, i32>(&key, book_id, -1).await?;
+        let prices_key = Self::prices_key(cart_id);
+        if quantity <= 0 {
+            redis_con.hdel::<_, _, ()>(&key, book_id).await?;
+            redis_con.hdel::<_, _, ()>(&prices_key, book_id).await?;
+        }
+        redis_con.expire::<_, ()>(&key, Self::ttl_secs()).await?;
+        redis_con
+            .expire::<_, ()>(&prices_key, Self::ttl_secs())
+            .await?;
+        Ok(())
+    }
+
+    // Move the books of the guest cart into the cart of the user who just logged in
+    pub async fn merge_into(
+        db: &Database,
+        redis_con: &mut ConnectionManager,
+        cart_id: &str,
+        user_id: i32,
+    ) -> Result<(), AppError> {
+        let items = Self::get_items(redis_con, cart_id).await?;
+        if items.is_empty() {
+            return Ok(());
+        }
+
+        // Items added before the prices were kept get the current price
+        let prices = redis_con
+            .hgetall::<_, HashMap<i32, i32>>(Self::prices_key(cart_id))
+            .await?;
+
+        // Creates the cart of the user if there is none yet
+        let cart_id_of_user = Cart::get_cart(db, user_id).await?.id;
+        let rule = CartMergeRule::from_env();
+
+        // All or nothing, a failed merge keeps the guest cart and can be repeated at the next login
+        let mut tx = db.pool.begin().await?;
+        for (book_id, quantity) in items {
+            // Books deleted since they were added are skipped
+            let max = match Cart::max_quantity(db, book_id).await {
+                Ok(max) => max,
+                Err(AppError::NotFound(_)) => continue,
+                Err(e) => return Err(e),
+            };
+
+            // A sum over the limit of the book is cut back to the limit, a book already in the cart
+            // of the user keeps the price it was added to that cart with
+            sqlx::query!(
+                r#"
+                INSERT INTO cart_items (cart_id, book_id, quantity, price_at_add)
+                VALUES (?, ?, LEAST(?, ?), COALESCE(?, (SELECT price FROM books WHERE id = ?)))
+                ON DUPLICATE KEY UPDATE quantity = LEAST(?, IF(?,
+                    quantity + VALUES(quantity),
+                    GREATEST(quantity, VALUES(quantity))
+                ))
+                "#,
+                cart_id_of_user,
+                book_id,
+                quantity,
+                max,
+                prices.get(&book_id),
+                book_id,
+                max,
+                rule == CartMergeRule::Sum
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        redis_con
+            .del::<_, ()>(&[Self::key(cart_id), Self::prices_key(cart_id)])
+            .await?;
+        Ok(())
+    }
+}
@@ -1,4 +1,11 @@
+pub mod account_deletion;
+pub mod audit_log;
 pub mod book;
 pub mod cart;
+pub mod cart_preview;
+pub mod data_export;
+pub mod guest_cart;
+pub mod role;
+pub mod two_factor;
 pub mod user;
 pub mod user_history;
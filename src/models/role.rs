@@ -0,0 +1,58 @@
+use serde::{Deserialize, Serialize};
+
+// Roles of the users, stored in `users.role`, an unknown value fails to load instead of
+// silently becoming a customer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum Role {
+    #[default]
+    Customer,
+    Support,
+    Warehouse,
+    CatalogEditor,
+    Admin,
+}
+
+// What a role may do besides using its own account, guarded routes declare the one they need
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Permission {
+    #[serde(rename = "books:write")]
+    BooksWrite,
+    #[serde(rename = "orders:read")]
+    OrdersRead,
+    #[serde(rename = "orders:update_status")]
+    OrdersUpdateStatus,
+    #[serde(rename = "users:read")]
+    UsersRead,
+    #[serde(rename = "users:write")]
+    UsersWrite,
+    #[serde(rename = "mails:manage")]
+    MailsManage,
+    #[serde(rename = "audit:read")]
+    AuditRead,
+}
+
+impl Role {
+    pub fn permissions(&self) -> &'static [Permission] {
+        match self {
+            Role::Customer => &[],
+            Role::Support => &[Permission::UsersRead, Permission::OrdersRead],
+            Role::Warehouse => &[Permission::OrdersRead, Permission::OrdersUpdateStatus],
+            Role::CatalogEditor => &[Permission::BooksWrite],
+            Role::Admin => &[
+                Permission::BooksWrite,
+                Permission::OrdersRead,
+                Permission::OrdersUpdateStatus,
+                Permission::UsersRead,
+                Permission::UsersWrite,
+                Permission::MailsManage,
+                Permission::AuditRead,
+            ],
+        }
+    }
+
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.permissions().contains(&permission)
+    }
+}
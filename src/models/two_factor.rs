@@ -0,0 +1,463 @@
+extern crate redis;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+
+use crate::database::Database;
+use crate::error::AppError;
+use crate::utils::{
+    credentials_hashing, email::Token, login_guard::LoginGuard, redis::Redis,
+    refresh_token::RefreshToken, totp::Totp,
+};
+
+use serde::Serialize;
+
+use super::audit_log::{AuditAction, AuditContext, AuditLog, AuditTarget};
+use super::role::Role;
+use super::user::User;
+
+const PENDING_LOGIN_TTL_SECS: u64 = 60 * 5;
+const PENDING_LOGIN_MAX_ATTEMPTS: i64 = 5;
+const SETUP_TOKEN_TTL_SECS: u64 = 60 * 15;
+
+// Result of the first login step
+pub enum LoginStep {
+    Authenticated(User),
+    TwoFactorPending(String),
+    // The user was forced to enrol, the token is only good for finishing the enrolment
+    TwoFactorSetupRequired(String),
+}
+
+#[derive(Debug, Serialize)]
+pub struct TwoFactorEnrollment {
+    pub secret: String,
+    pub otpauth_uri: String,
+    pub qr_code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TwoFactorStatus {
+    pub enabled: bool,
+    pub required: bool,
+}
+
+pub struct TwoFactor;
+
+impl TwoFactor {
+    pub async fn get_status(db: &Database, user_id: i32) -> Result<TwoFactorStatus, AppError> {
+        let status = sqlx::query_as!(
+            TwoFactorStatus,
+            r#"
+            SELECT totp_enabled AS `enabled: bool`, totp_required AS `required: bool`
+            FROM users
+            WHERE id = ?
+            "#,
+            user_id
+        )
+        .fetch_optional(&db.pool)
+        .await?;
+
+        match status {
+            Some(status) => Ok(status),
+            None => Err(AppError::NotFound("user_not_found")),
+        }
+    }
+
+    // Check if the user was forced to enrol but hasn't done it yet
+    pub async fn is_setup_pending(db: &Database, user_id: i32) -> Result<bool, AppError> {
+        let status = Self::get_status(db, user_id).await?;
+        Ok(status.required && !status.enabled)
+    }
+
+    // Start the enrolment with a new secret, it is only active after the first code is confirmed
+    pub async fn enroll(db: &Database, user_id: i32) -> Result<TwoFactorEnrollment, AppError> {
+        let user = sqlx::query!(
+            r#"SELECT username, totp_enabled AS `totp_enabled: bool` FROM users WHERE id = ?"#,
+            user_id
+        )
+        .fetch_optional(&db.pool)
+        .await?;
+
+        let Some(user) = user else {
+            return Err(AppError::NotFound("user_not_found"));
+        };
+
+        if user.totp_enabled {
+            return Err(AppError::Conflict("two_factor_already_enabled"));
+        }
+
+        let secret = Totp::generate_secret();
+        sqlx::query!(
+            r#"UPDATE users SET totp_secret = ? WHERE id = ?"#,
+            secret,
+            user_id
+        )
+        .execute(&db.pool)
+        .await?;
+
+        Ok(TwoFactorEnrollment {
+            otpauth_uri: Totp::get_url(&secret, &user.username)?,
+            qr_code: Totp::get_qr_code(&secret, &user.username)?,
+            secret,
+        })
+    }
+
+    // Turn on 2FA with the first code from the app, returns the recovery codes
+    pub async fn confirm(
+        db: &Database,
+        redis_con: &mut ConnectionManager,
+        user_id: i32,
+        code: &str,
+    ) -> Result<Vec<String>, AppError> {
+        let user = sqlx::query!(
+            r#"
+            SELECT totp_secret, totp_enabled AS `totp_enabled: bool`
+            FROM users
+            WHERE id = ?
+            "#,
+            user_id
+        )
+        .fetch_optional(&db.pool)
+        .await?;
+
+        let Some(user) = user else {
+            return Err(AppError::NotFound("user_not_found"));
+        };
+
+        if user.totp_enabled {
+            return Err(AppError::Conflict("two_factor_already_enabled"));
+        }
+        let Some(secret) = user.totp_secret else {
+            return Err(AppError::BadRequest("two_factor_not_started"));
+        };
+
+        if !Totp::check(redis_con, user_id, &secret, code).await? {
+            return Err(AppError::BadRequest("invalid_two_factor_code"));
+        }
+
+        sqlx::query!(
+            r#"UPDATE users SET totp_enabled = TRUE WHERE id = ?"#,
+            user_id
+        )
+        .execute(&db.pool)
+        .await?;
+
+        Self::replace_recovery_codes(db, user_id).await
+    }
+
+    // Turn off 2FA, needs the password and a valid code
+    pub async fn disable(
+        db: &Database,
+        redis_con: &mut ConnectionManager,
+        user_id: i32,
+        password: &str,
+        code: &str,
+    ) -> Result<(), AppError> {
+        let user = sqlx::query!(
+            r#"SELECT password, totp_required AS `totp_required: bool` FROM users WHERE id = ?"#,
+            user_id
+        )
+        .fetch_optional(&db.pool)
+        .await?;
+
+        let Some(user) = user else {
+            return Err(AppError::NotFound("user_not_found"));
+        };
+
+        if user.totp_required {
+            return Err(AppError::Forbidden("two_factor_required"));
+        }
+        if !credentials_hashing::verify_password(password, &user.password) {
+            return Err(AppError::Forbidden("invalid_password"));
+        }
+        Self::verify(db, redis_con, user_id, code).await?;
+
+        sqlx::query!(
+            r#"UPDATE users SET totp_secret = NULL, totp_enabled = FALSE WHERE id = ?"#,
+            user_id
+        )
+        .execute(&db.pool)
+        .await?;
+
+        sqlx::query!(
+            r#"DELETE FROM user_recovery_codes WHERE user_id = ?"#,
+            user_id
+        )
+        .execute(&db.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    // Replace the recovery codes, the old ones stop working
+    pub async fn regenerate_recovery_codes(
+        db: &Database,
+        redis_con: &mut ConnectionManager,
+        user_id: i32,
+        code: &str,
+    ) -> Result<Vec<String>, AppError> {
+        Self::verify(db, redis_con, user_id, code).await?;
+        Self::replace_recovery_codes(db, user_id).await
+    }
+
+    // Check a code from the app or an unused recovery code
+    pub async fn verify(
+        db: &Database,
+        redis_con: &mut ConnectionManager,
+        user_id: i32,
+        code: &str,
+    ) -> Result<(), AppError> {
+        let user = sqlx::query!(
+            r#"
+            SELECT totp_secret, totp_enabled AS `totp_enabled: bool`
+            FROM users
+            WHERE id = ?
+            "#,
+            user_id
+        )
+        .fetch_optional(&db.pool)
+        .await?;
+
+        let Some(user) = user else {
+            return Err(AppError::NotFound("user_not_found"));
+        };
+
+        let secret = match user.totp_secret {
+            Some(secret) if user.totp_enabled => secret,
+            _ => return Err(AppError::BadRequest("two_factor_not_enabled")),
+        };
+
+        let code = code.trim();
+        if Totp::check(redis_con, user_id, &secret, code).await? {
+            return Ok(());
+        }
+        if !Totp::is_recovery_code(code) {
+            return Err(AppError::BadRequest("invalid_two_factor_code"));
+        }
+
+        let recovery_codes = sqlx::query!(
+            r#"SELECT id, code_hash FROM user_recovery_codes WHERE user_id = ?"#,
+            user_id
+        )
+        .fetch_all(&db.pool)
+        .await?;
+
+        let code = code.to_lowercase();
+        for recovery_code in recovery_codes {
+            if credentials_hashing::verify_password(&code, &recovery_code.code_hash) {
+                sqlx::query!(
+                    r#"DELETE FROM user_recovery_codes WHERE id = ?"#,
+                    recovery_code.id
+                )
+                .execute(&db.pool)
+                .await?;
+                return Ok(());
+            }
+        }
+
+        Err(AppError::BadRequest("invalid_two_factor_code"))
+    }
+
+    // Log the user in right away, or hand out a pending token if 2FA is enabled
+    pub async fn login_step(
+        db: &Database,
+        redis_con: &mut ConnectionManager,
+        user: User,
+    ) -> Result<LoginStep, AppError> {
+        let user_id = user.id.unwrap();
+        User::ensure_enabled(db, user_id).await?;
+        let status = Self::get_status(db, user_id).await?;
+        if status.required && !status.enabled {
+            let setup_token = Token::generate_reset_token();
+            Redis::set_token_to_user_with_expiry(
+                redis_con,
+                user_id as u32,
+                &Redis::token_key("2fa-setup", &setup_token),
+                SETUP_TOKEN_TTL_SECS,
+            )
+            .await?;
+            return Ok(LoginStep::TwoFactorSetupRequired(setup_token));
+        }
+        if !status.enabled {
+            return Ok(LoginStep::Authenticated(user));
+        }
+
+        let pending_token = Token::generate_reset_token();
+        Redis::set_token_to_user_with_expiry(
+            redis_con,
+            user_id as u32,
+            &Redis::token_key("2fa-pending", &pending_token),
+            PENDING_LOGIN_TTL_SECS,
+        )
+        .await?;
+        Ok(LoginStep::TwoFactorPending(pending_token))
+    }
+
+    // Finish a login started with a password or an e-mail code
+    pub async fn complete_login(
+        db: &Database,
+        redis_con: &mut ConnectionManager,
+        pending_token: &str,
+        code: &str,
+        audit: &AuditContext,
+    ) -> Result<User, AppError> {
+        let token_key = Redis::token_key("2fa-pending", pending_token);
+        let Some(user_id) = Redis::get_user_id_by_token(redis_con, &token_key).await? else {
+            return Err(AppError::Unauthorized("invalid_token"));
+        };
+
+        // Wrong codes count against the account like wrong passwords, a new pending token
+        // doesn't reset them
+        LoginGuard::check(redis_con, Some(user_id), &audit.ip).await?;
+        if let Err(e) = Self::verify(db, redis_con, user_id, code).await {
+            if let AppError::BadRequest("invalid_two_factor_code") = e {
+                LoginGuard::record_failure(redis_con, Some(user_id), &audit.ip).await?;
+            }
+            let changes = Some(serde_json::json!({ "method": "two_factor" }));
+            let target = AuditTarget::User(user_id);
+            AuditLog::record(db, audit, AuditAction::LoginFailed, target, changes).await;
+
+            let attempts_key = Redis::token_key("2fa-pending-attempts", pending_token);
+            let attempts = redis_con.incr::<_, _, i64>(&attempts_key, 1).await?;
+            redis_con
+                .expire::<_, ()>(&attempts_key, PENDING_LOGIN_TTL_SECS as i64)
+                .await?;
+
+            // Start over with the password after too many wrong codes
+            if attempts >= PENDING_LOGIN_MAX_ATTEMPTS {
+                redis_con.del::<_, ()>(&token_key).await?;
+            }
+            return Err(e);
+        }
+
+        redis_con.del::<_, ()>(&token_key).await?;
+        Self::finish_login(db, redis_con, user_id, "two_factor", audit).await
+    }
+
+    async fn setup_token_user(
+        redis_con: &mut ConnectionManager,
+        setup_token: &str,
+    ) -> Result<i32, AppError> {
+        let token_key = Redis::token_key("2fa-setup", setup_token);
+        match Redis::get_user_id_by_token(redis_con, &token_key).await? {
+            Some(user_id) => Ok(user_id),
+            None => Err(AppError::Unauthorized("invalid_token")),
+        }
+    }
+
+    // The forced enrolment, started with the setup token of the login
+    pub async fn enroll_with_setup_token(
+        db: &Database,
+        redis_con: &mut ConnectionManager,
+        setup_token: &str,
+    ) -> Result<TwoFactorEnrollment, AppError> {
+        let user_id = Self::setup_token_user(redis_con, setup_token).await?;
+        Self::enroll(db, user_id).await
+    }
+
+    // Confirming the forced enrolment completes the login, returns the user and the recovery codes
+    pub async fn confirm_with_setup_token(
+        db: &Database,
+        redis_con: &mut ConnectionManager,
+        setup_token: &str,
+        code: &str,
+        audit: &AuditContext,
+    ) -> Result<(User, Vec<String>), AppError> {
+        let user_id = Self::setup_token_user(redis_con, setup_token).await?;
+        let recovery_codes = Self::confirm(db, redis_con, user_id, code).await?;
+
+        redis_con
+            .del::<_, ()>(Redis::token_key("2fa-setup", setup_token))
+            .await?;
+        let user = Self::finish_login(db, redis_con, user_id, "two_factor_setup", audit).await?;
+        Ok((user, recovery_codes))
+    }
+
+    async fn finish_login(
+        db: &Database,
+        redis_con: &mut ConnectionManager,
+        user_id: i32,
+        method: &str,
+        audit: &AuditContext,
+    ) -> Result<User, AppError> {
+        LoginGuard::record_success(redis_con, user_id).await?;
+        User::ensure_enabled(db, user_id).await?;
+
+        let user = sqlx::query_as!(
+            User,
+            r#"SELECT id, username, email, password, role AS `role: Role` FROM users WHERE id = ?"#,
+            user_id
+        )
+        .fetch_optional(&db.pool)
+        .await?;
+
+        let Some(user) = user else {
+            return Err(AppError::NotFound("user_not_found"));
+        };
+
+        let changes = Some(serde_json::json!({ "method": method }));
+        let audit = audit.with_actor(user_id);
+        let target = AuditTarget::User(user_id);
+        AuditLog::record(db, &audit, AuditAction::Login, target, changes).await;
+        Ok(user)
+    }
+
+    // Force or release the 2FA enrolment of a user
+    pub async fn set_required(
+        db: &Database,
+        user_id: i32,
+        required: bool,
+        audit: &AuditContext,
+    ) -> Result<(), AppError> {
+        let result = sqlx::query!(
+            r#"UPDATE users SET totp_required = ? WHERE id = ?"#,
+            required,
+            user_id
+        )
+        .execute(&db.pool)
+        .await?;
+
+        if result.rows_affected() == 0 && !User::is_user_exists(db, user_id).await? {
+            return Err(AppError::NotFound("user_not_found"));
+        }
+
+        // The sessions started without 2FA end, the next login leads to the enrolment
+        if required && Self::is_setup_pending(db, user_id).await? {
+            let mut redis_con = db.redis.clone();
+            RefreshToken::revoke_all(&mut redis_con, user_id as usize).await?;
+        }
+
+        let changes = Some(serde_json::json!({ "totp_required": required }));
+        let target = AuditTarget::User(user_id);
+        AuditLog::record(
+            db,
+            audit,
+            AuditAction::TwoFactorRequirementChanged,
+            target,
+            changes,
+        )
+        .await;
+        Ok(())
+    }
+
+    async fn replace_recovery_codes(db: &Database, user_id: i32) -> Result<Vec<String>, AppError> {
+        sqlx::query!(
+            r#"DELETE FROM user_recovery_codes WHERE user_id = ?"#,
+            user_id
+        )
+        .execute(&db.pool)
+        .await?;
+
+        let recovery_codes = Totp::generate_recovery_codes();
+        for code in recovery_codes.iter() {
+            sqlx::query!(
+                r#"INSERT INTO user_recovery_codes(user_id, code_hash) VALUES(?, ?)"#,
+                user_id,
+                credentials_hashing::hash_password(code)
+            )
+            .execute(&db.pool)
+            .await?;
+        }
+
+        Ok(recovery_codes)
+    }
+}
@@ -1,9 +1,15 @@
 // src/models/user.rs
 
 extern crate redis;
-use redis::Commands;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
 
 use crate::database::Database;
+use crate::error::AppError;
+use crate::i18n::Locale;
+use crate::models::audit_log::{AuditAction, AuditContext, AuditLog, AuditTarget};
+use crate::models::role::Role;
+use crate::models::two_factor::{LoginStep, TwoFactor};
 use crate::scopes::user::{
     ChangeBillingInformationJson, ChangeEmailJson, ChangePersonalInformationJson,
     ChangeUsernameJson,
@@ -11,30 +17,20 @@ use crate::scopes::user::{
 use crate::utils::{
     credentials_hashing,
     email::{Email, Token},
+    login_guard::LoginGuard,
+    one_time_token::{LoginCode, ResetToken},
+    password_policy::PasswordPolicy,
     redis::Redis,
+    refresh_token::RefreshToken,
 };
 
+use lettre::Address;
 use serde::{Deserialize, Serialize};
-use std::error::Error;
-
-// Enum representing user groups
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
-pub enum UserGroup {
-    User,
-    Admin,
-    None,
-}
 
-// Implement conversion from String to UserGroup
-impl From<String> for UserGroup {
-    fn from(s: String) -> Self {
-        match s.as_str() {
-            "Admin" => UserGroup::Admin,
-            "User" => UserGroup::User,
-            _ => UserGroup::User,
-        }
-    }
-}
+const EMAIL_VERIFICATION_TTL_SECS: u64 = 60 * 60 * 24;
+const EMAIL_VERIFICATION_COOLDOWN_SECS: u64 = 60;
+const EMAIL_CHANGE_TTL_SECS: u64 = 60 * 60 * 24;
+const EMAIL_CHANGE_REVERT_TTL_SECS: u64 = 60 * 60 * 24 * 7;
 
 // User struct representing a user in the system
 #[derive(Debug, Serialize, Deserialize)]
@@ -43,7 +39,16 @@ pub struct User {
     pub email: Option<String>,
     pub username: Option<String>,
     pub password: Option<String>,
-    pub group: UserGroup,
+    pub role: Role,
+}
+
+// Pending e-mail change stored in Redis until it is confirmed or reverted
+#[derive(Debug, Serialize, Deserialize)]
+struct EmailChangeRequest {
+    user_id: i32,
+    old_email: String,
+    new_email: String,
+    confirm_token: String,
 }
 
 // UserInfo struct representing additional user information
@@ -58,16 +63,66 @@ pub struct UserInfo {
     pub postal_code: String,
     pub username: String,
     pub email: String,
+    pub email_verified: bool,
+}
+
+// A row of the user search of the admins
+#[derive(Debug, Serialize)]
+pub struct UserSummary {
+    pub id: i32,
+    pub username: String,
+    pub email: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub role: Role,
+    pub disabled: bool,
+    pub email_verified: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserSearchPage {
+    pub users: Vec<UserSummary>,
+    pub page: u32,
+    pub per_page: u32,
+    pub total: i64,
+}
+
+// Everything the admins see about a single user
+#[derive(Debug, Serialize)]
+pub struct UserDetails {
+    pub id: i32,
+    pub role: Role,
+    pub disabled: bool,
+    #[serde(flatten)]
+    pub info: UserInfo,
+}
+
+// Match the search text literally, `%` and `_` would be wildcards in LIKE
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
 }
 
 impl User {
     // Create a new user
-    pub async fn new(db: &Database, user: User) -> Result<Self, Box<dyn Error>> {
+    pub async fn new(db: &Database, user: User, locale: Locale) -> Result<Self, AppError> {
         // Check for required fields
         if user.username.is_none() || user.password.is_none() || user.email.is_none() {
-            return Err("Minden mező (e-mail, felhasználónév, jelszó) kitöltése kötelező".into());
+            return Err(AppError::BadRequest("missing_fields"));
+        }
+
+        if user.email.as_ref().unwrap().parse::<Address>().is_err() {
+            return Err(AppError::BadRequest("invalid_email"));
         }
 
+        PasswordPolicy::get().check(
+            "password",
+            user.password.as_ref().unwrap(),
+            user.username.as_ref().unwrap(),
+            user.email.as_ref().unwrap(),
+        )?;
+
         // Check if user already exists
         let is_exists = sqlx::query!(
             r#"SELECT * from users WHERE email = ? OR username = ?"#,
@@ -78,16 +133,17 @@ impl User {
         .await?;
 
         if is_exists.is_some() {
-            return Err("A felhasználó már létezik!".into());
+            return Err(AppError::Conflict("user_exists"));
         }
 
         // Hash the password and insert the new user
         let hashed_password = credentials_hashing::hash_password(&user.password.unwrap());
         let result = sqlx::query!(
-            r#"INSERT INTO users(username, email, password) VALUES(?, ?, ?)"#,
+            r#"INSERT INTO users(username, email, password, locale) VALUES(?, ?, ?, ?)"#,
             user.username,
             user.email,
-            hashed_password
+            hashed_password,
+            locale.code()
         )
         .execute(&db.pool)
         .await?;
@@ -106,16 +162,102 @@ impl User {
             email: user.email,
             username: user.username,
             password: Some(hashed_password),
-            group: UserGroup::User,
+            role: Role::Customer,
         })
     }
 
+    // Send a link for confirming the user's e-mail address
+    pub async fn send_verification_email(
+        db: &Database,
+        redis_con: &mut ConnectionManager,
+        user_id: i32,
+    ) -> Result<(), AppError> {
+        let user = sqlx::query!(
+            r#"SELECT email, email_verified AS `email_verified: bool` FROM users WHERE id = ?"#,
+            user_id
+        )
+        .fetch_optional(&db.pool)
+        .await?;
+
+        let Some(user) = user else {
+            return Err(AppError::NotFound("user_not_found"));
+        };
+
+        if user.email_verified {
+            return Err(AppError::Conflict("email_already_verified"));
+        }
+
+        let cooldown_key = format!("email-verification-cooldown:{user_id}");
+        if let Some(remaining) =
+            Redis::try_start_cooldown(redis_con, &cooldown_key, EMAIL_VERIFICATION_COOLDOWN_SECS)
+                .await?
+        {
+            return Err(AppError::TooManyRequests(
+                "verification_cooldown",
+                remaining,
+            ));
+        }
+
+        let verification_token = Token::generate_reset_token();
+        Redis::set_token_to_user_with_expiry(
+            redis_con,
+            user_id as u32,
+            &Redis::token_key("email-verification", &verification_token),
+            EMAIL_VERIFICATION_TTL_SECS,
+        )
+        .await?;
+        let locale = Self::get_locale(db, user_id).await?;
+        let result =
+            Email::send_verification_email(redis_con, locale, &user.email, &verification_token)
+                .await;
+        // A mail that was never queued shouldn't hold up asking for a new one
+        if result.is_err() {
+            redis_con.del::<_, ()>(&cooldown_key).await?;
+        }
+        result
+    }
+
+    // Mark the user's e-mail address as verified
+    pub async fn verify_email(
+        db: &Database,
+        redis_con: &mut ConnectionManager,
+        verification_token: &str,
+    ) -> Result<(), AppError> {
+        let token_key = Redis::token_key("email-verification", verification_token);
+        let Some(user_id) = Redis::take_user_id_by_token(redis_con, &token_key).await? else {
+            return Err(AppError::BadRequest("invalid_token"));
+        };
+
+        sqlx::query!(
+            r#"UPDATE users SET email_verified = TRUE WHERE id = ?"#,
+            user_id
+        )
+        .execute(&db.pool)
+        .await?;
+        Ok(())
+    }
+
+    // Check if the user has confirmed their e-mail address
+    pub async fn is_email_verified(db: &Database, id: i32) -> Result<bool, AppError> {
+        let user = sqlx::query!(
+            r#"SELECT email_verified AS `email_verified: bool` FROM users WHERE id = ?"#,
+            id
+        )
+        .fetch_optional(&db.pool)
+        .await?;
+
+        match user {
+            Some(user) => Ok(user.email_verified),
+            None => Err(AppError::NotFound("user_not_found")),
+        }
+    }
+
     // Get user information
-    pub async fn get_info(db: &Database, user_id: i32) -> Result<UserInfo, Box<dyn Error>> {
+    pub async fn get_info(db: &Database, user_id: i32) -> Result<UserInfo, AppError> {
         let user_info = sqlx::query_as!(
             UserInfo,
             r#"
-            SELECT first_name, last_name, phone_number, billing_address, city, state_province, postal_code, email, username
+            SELECT first_name, last_name, phone_number, billing_address, city, state_province, postal_code, email, username, email_verified AS `email_verified: bool`
             FROM user_info
             JOIN users ON user_info.user_id = users.id
             WHERE user_info.user_id = ?
@@ -127,126 +269,237 @@ impl User {
 
         match user_info {
             Some(info) => Ok(info),
-            None => Err("A felhasználói adatok nem találhatók".into()),
+            None => Err(AppError::NotFound("user_not_found")),
         }
     }
 
-    // Login with username and password
-    pub async fn login_with_password(db: &Database, user: User) -> Result<Self, Box<dyn Error>> {
+    // Login with username and password, users with 2FA still have to send a code
+    pub async fn login_with_password(
+        db: &Database,
+        redis_con: &mut ConnectionManager,
+        user: User,
+        audit: &AuditContext,
+    ) -> Result<LoginStep, AppError> {
+        let ip = audit.ip.as_str();
+        LoginGuard::check(redis_con, None, ip).await?;
+
         let user_data = sqlx::query_as!(
             Self,
-            r#"SELECT * FROM users WHERE username = ?"#,
+            r#"SELECT id, username, email, password, role AS `role: Role` FROM users WHERE username = ?"#,
             user.username
         )
         .fetch_optional(&db.pool)
         .await?;
 
         let Some(hashed_user) = user_data else {
-            return Err("A felhasználó nem található".into());
+            LoginGuard::record_failure(redis_con, None, ip).await?;
+            let changes = Some(serde_json::json!({ "username": user.username }));
+            AuditLog::record(
+                db,
+                audit,
+                AuditAction::LoginFailed,
+                AuditTarget::None,
+                changes,
+            )
+            .await;
+            return Err(AppError::Unauthorized("user_not_found"));
         };
+        LoginGuard::check(redis_con, hashed_user.id, ip).await?;
+        let user_id = hashed_user.id.unwrap();
 
-        if credentials_hashing::verify_password(
-            &user.password.unwrap(),
-            hashed_user.password.as_ref().unwrap(),
-        ) {
-            Ok(Self {
+        let password = user.password.unwrap();
+        if credentials_hashing::verify_password(&password, hashed_user.password.as_ref().unwrap()) {
+            // Upgrade hashes made with older Argon2 parameters while the password is at hand
+            if credentials_hashing::needs_rehash(hashed_user.password.as_ref().unwrap()) {
+                sqlx::query!(
+                    "UPDATE users SET password = ? WHERE id = ?",
+                    credentials_hashing::hash_password(&password),
+                    hashed_user.id
+                )
+                .execute(&db.pool)
+                .await?;
+            }
+
+            let user = Self {
                 id: hashed_user.id,
                 email: hashed_user.email,
                 username: hashed_user.username,
                 password: Some(hashed_user.password.unwrap()),
-                group: hashed_user.group,
-            })
+                role: hashed_user.role,
+            };
+            let login_step = TwoFactor::login_step(db, redis_con, user).await?;
+            Self::login_succeeded(db, redis_con, audit, user_id, &login_step, "password").await?;
+            Ok(login_step)
         } else {
-            Err("A jelszó érvénytelen".into())
+            LoginGuard::record_failure(redis_con, hashed_user.id, ip).await?;
+            let changes = Some(serde_json::json!({ "method": "password" }));
+            let target = AuditTarget::User(user_id);
+            AuditLog::record(db, audit, AuditAction::LoginFailed, target, changes).await;
+            Err(AppError::Unauthorized("invalid_password"))
         }
     }
 
-    // Login with email and token
+    // Login with the e-mail address and the code sent to it
     pub(crate) async fn login_with_email(
         db: &Database,
-        redis_con: &mut redis::Connection,
+        redis_con: &mut ConnectionManager,
+        email: &str,
         code: &str,
-    ) -> Result<Self, Box<dyn Error>> {
-        let user_id = Redis::get_user_id_by_token(redis_con, code)?;
+        audit: &AuditContext,
+    ) -> Result<LoginStep, AppError> {
+        let ip = audit.ip.as_str();
+        LoginGuard::check(redis_con, None, ip).await?;
 
-        let user_data = sqlx::query_as!(Self, r#"SELECT * FROM users WHERE id = ?"#, user_id)
-            .fetch_optional(&db.pool)
-            .await?;
+        let user_data = sqlx::query_as!(
+            Self,
+            r#"SELECT id, username, email, password, role AS `role: Role` FROM users WHERE email = ?"#,
+            email
+        )
+        .fetch_optional(&db.pool)
+        .await?;
 
         let Some(hashed_user) = user_data else {
-            return Err("A felhasználó nem található".into());
+            LoginGuard::record_failure(redis_con, None, ip).await?;
+            return Err(AppError::Unauthorized("invalid_email_code"));
         };
+        let user_id = hashed_user.id.unwrap();
+        LoginGuard::check(redis_con, Some(user_id), ip).await?;
 
-        redis_con.del::<_, String>(code)?;
-        Ok(Self {
+        // The code only works for the account it was sent to
+        if !LoginCode::consume(redis_con, user_id, code).await? {
+            LoginGuard::record_failure(redis_con, Some(user_id), ip).await?;
+            let changes = Some(serde_json::json!({ "method": "email_code" }));
+            let target = AuditTarget::User(user_id);
+            AuditLog::record(db, audit, AuditAction::LoginFailed, target, changes).await;
+            return Err(AppError::Unauthorized("invalid_email_code"));
+        }
+
+        let user = Self {
             id: hashed_user.id,
             email: hashed_user.email,
             username: hashed_user.username,
             password: Some(hashed_user.password.unwrap()),
-            group: hashed_user.group,
-        })
+            role: hashed_user.role,
+        };
+        let login_step = TwoFactor::login_step(db, redis_con, user).await?;
+        Self::login_succeeded(db, redis_con, audit, user_id, &login_step, "email_code").await?;
+        Ok(login_step)
+    }
+
+    // A login only counts once it is complete, with 2FA the failures are kept until the code is
+    // right too, so guessing codes can't start over with the password
+    async fn login_succeeded(
+        db: &Database,
+        redis_con: &mut ConnectionManager,
+        audit: &AuditContext,
+        user_id: i32,
+        login_step: &LoginStep,
+        method: &str,
+    ) -> Result<(), AppError> {
+        if let LoginStep::Authenticated(_) = login_step {
+            LoginGuard::record_success(redis_con, user_id).await?;
+
+            let changes = Some(serde_json::json!({ "method": method }));
+            let target = AuditTarget::User(user_id);
+            let audit = audit.with_actor(user_id);
+            AuditLog::record(db, &audit, AuditAction::Login, target, changes).await;
+        }
+        Ok(())
     }
 
     // Send authentication code for password reset
     pub async fn send_authentication_code(
         db: &Database,
-        redis_con: &mut redis::Connection,
+        redis_con: &mut ConnectionManager,
         user: User,
-    ) -> Result<(), Box<dyn Error>> {
-        let user = sqlx::query_as!(Self, "SELECT * FROM users WHERE email = ?", user.email)
-            .fetch_optional(&db.pool)
-            .await?;
+    ) -> Result<(), AppError> {
+        let user = sqlx::query_as!(
+            Self,
+            "SELECT id, username, email, password, role AS `role: Role` FROM users WHERE email = ?",
+            user.email
+        )
+        .fetch_optional(&db.pool)
+        .await?;
 
         let Some(user) = user else {
-            return Err("Az e-mail nem található".into());
+            return Err(AppError::NotFound("email_not_found"));
         };
 
-        let reset_token = Token::generate_six_digit_number();
-        Redis::set_token_to_user(redis_con, user.id.unwrap() as u32, &reset_token)?;
-        Email::send_authentication_code(&user.email.unwrap(), &reset_token).await?;
+        let code = LoginCode::issue(redis_con, user.id.unwrap()).await?;
+        let locale = Self::get_locale(db, user.id.unwrap()).await?;
+        Email::send_authentication_code(redis_con, locale, &user.email.unwrap(), &code).await?;
         Ok(())
     }
 
     // Handle password reset request
     pub async fn forgot_password(
         db: &Database,
-        redis_con: &mut redis::Connection,
+        redis_con: &mut ConnectionManager,
         user: User,
-    ) -> Result<(), Box<dyn Error>> {
-        let user = sqlx::query_as!(Self, "SELECT * FROM users WHERE email = ?", user.email)
-            .fetch_optional(&db.pool)
-            .await?;
+    ) -> Result<(), AppError> {
+        let user = sqlx::query_as!(
+            Self,
+            "SELECT id, username, email, password, role AS `role: Role` FROM users WHERE email = ?",
+            user.email
+        )
+        .fetch_optional(&db.pool)
+        .await?;
 
         let Some(user) = user else {
-            return Err("A felhasználó nem található".into());
+            return Err(AppError::NotFound("user_not_found"));
         };
 
-        let reset_token = Token::generate_reset_token();
-        Redis::set_token_to_user(redis_con, user.id.unwrap() as u32, &reset_token)?;
-        Email::send_password_reset_email(&user.email.unwrap(), &reset_token).await?;
+        Self::send_password_reset(db, redis_con, user.id.unwrap(), &user.email.unwrap()).await
+    }
+
+    // Send a password reset link to the user
+    pub async fn send_password_reset(
+        db: &Database,
+        redis_con: &mut ConnectionManager,
+        user_id: i32,
+        email: &str,
+    ) -> Result<(), AppError> {
+        let reset_token = ResetToken::issue(redis_con, user_id).await?;
+        let locale = Self::get_locale(db, user_id).await?;
+        Email::send_password_reset_email(redis_con, locale, email, &reset_token).await?;
         Ok(())
     }
 
     // Reset user's password
     pub async fn reset_password(
         db: &Database,
-        redis_con: &mut redis::Connection,
+        redis_con: &mut ConnectionManager,
         reset_token: String,
         new_password: String,
-    ) -> Result<(), Box<dyn Error>> {
-        let user_id = Redis::get_user_id_by_token(redis_con, &reset_token)?;
-        if user_id == -1 {
-            return Err("Nem létező token!".into());
-        }
+        audit: &AuditContext,
+    ) -> Result<(), AppError> {
+        let Some(user_id) = ResetToken::peek(redis_con, &reset_token).await? else {
+            return Err(AppError::BadRequest("invalid_token"));
+        };
 
-        let user = sqlx::query_as!(Self, "SELECT * FROM users WHERE id = ?", user_id)
-            .fetch_optional(&db.pool)
-            .await?;
+        let user = sqlx::query_as!(
+            Self,
+            "SELECT id, username, email, password, role AS `role: Role` FROM users WHERE id = ?",
+            user_id
+        )
+        .fetch_optional(&db.pool)
+        .await?;
 
         let Some(user) = user else {
-            return Err("A felhasználó nem található".into());
+            return Err(AppError::NotFound("user_not_found"));
         };
 
+        // A rejected password doesn't use up the link
+        PasswordPolicy::get().check(
+            "password",
+            &new_password,
+            user.username.as_deref().unwrap_or_default(),
+            user.email.as_deref().unwrap_or_default(),
+        )?;
+        if ResetToken::consume(redis_con, &reset_token).await? != Some(user_id) {
+            return Err(AppError::BadRequest("invalid_token"));
+        }
+
         let hashed_password = credentials_hashing::hash_password(&new_password);
         let _ = sqlx::query!(
             "UPDATE users SET password = ? WHERE id = ?",
@@ -256,7 +509,9 @@ impl User {
         .execute(&db.pool)
         .await?;
 
-        redis_con.del::<_, ()>(&reset_token)?;
+        let audit = audit.with_actor(user_id);
+        let target = AuditTarget::User(user_id);
+        AuditLog::record(db, &audit, AuditAction::PasswordReset, target, None).await;
         Ok(())
     }
 
@@ -266,18 +521,20 @@ impl User {
         user_id: i32,
         old_password: String,
         new_password: String,
-    ) -> Result<(), Box<dyn Error>> {
+        audit: &AuditContext,
+    ) -> Result<(), AppError> {
         let user = sqlx::query!("SELECT * FROM users WHERE id = ?", user_id)
             .fetch_optional(&db.pool)
             .await?;
 
         let Some(user) = user else {
-            return Err("A felhasználó nem található".into());
+            return Err(AppError::NotFound("user_not_found"));
         };
 
         if !credentials_hashing::verify_password(&old_password, &user.password) {
-            return Err("A régi jelszó helytelen".into());
+            return Err(AppError::Forbidden("invalid_old_password"));
         }
+        PasswordPolicy::get().check("new_password", &new_password, &user.username, &user.email)?;
 
         let hashed_password = credentials_hashing::hash_password(&new_password);
         let _ = sqlx::query!(
@@ -288,6 +545,8 @@ impl User {
         .execute(&db.pool)
         .await?;
 
+        let target = AuditTarget::User(user_id);
+        AuditLog::record(db, audit, AuditAction::PasswordChanged, target, None).await;
         Ok(())
     }
 
@@ -296,7 +555,7 @@ impl User {
         db: &Database,
         id: i32,
         data: ChangePersonalInformationJson,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<(), AppError> {
         let _ = sqlx::query!(
             r#"
             UPDATE user_info
@@ -319,7 +578,7 @@ impl User {
         db: &Database,
         id: i32,
         data: ChangeBillingInformationJson,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<(), AppError> {
         let _ = sqlx::query!(
             r#"
             UPDATE user_info
@@ -338,61 +597,198 @@ impl User {
         Ok(())
     }
 
-    // Delete user account
-    pub async fn delete_account(db: &Database, id: i32) -> Result<(), Box<dyn Error>> {
-        let _ = sqlx::query!(
-            r#"
-                DELETE FROM users
-                WHERE id = ?
-                "#,
-            id,
-        )
-        .execute(&db.pool)
-        .await?;
-
-        Ok(())
-    }
-
-    // Change user's email
+    // Request an e-mail change, the address is only swapped after confirming the new one
     pub(crate) async fn change_email(
         db: &Database,
+        redis_con: &mut ConnectionManager,
         id: i32,
         data: ChangeEmailJson,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<(), AppError> {
+        if data.new_email.parse::<Address>().is_err() {
+            return Err(AppError::BadRequest("invalid_email"));
+        }
+
         let is_exists = sqlx::query!("SELECT * FROM users WHERE email = ?", data.new_email)
             .fetch_optional(&db.pool)
             .await?;
 
         if is_exists.is_some() {
-            return Err("Az e-mail cím már létezik".into());
+            return Err(AppError::Conflict("email_exists"));
         }
 
-        let user_data = sqlx::query_as!(Self, r#"SELECT * FROM users WHERE id = ?"#, id)
-            .fetch_optional(&db.pool)
-            .await?;
+        let user_data = sqlx::query_as!(
+            Self,
+            r#"SELECT id, username, email, password, role AS `role: Role` FROM users WHERE id = ?"#,
+            id
+        )
+        .fetch_optional(&db.pool)
+        .await?;
 
         let Some(hashed_user) = user_data else {
-            return Err("A felhasználó nem található".into());
+            return Err(AppError::NotFound("user_not_found"));
         };
-        if credentials_hashing::verify_password(
+        if !credentials_hashing::verify_password(
             &data.password,
             hashed_user.password.as_ref().unwrap(),
         ) {
-            let _ = sqlx::query!(
-                r#"
-                UPDATE users
-                SET email = ?
-                WHERE id = ?
-                "#,
-                data.new_email,
-                id
+            return Err(AppError::Forbidden("invalid_password"));
+        }
+
+        // Only the latest request can be confirmed
+        let pending_key = format!("email-change-pending:{id}");
+        if let Some(previous_token) = redis_con.get::<_, Option<String>>(&pending_key).await? {
+            redis_con
+                .del::<_, ()>(format!("email-change:{previous_token}"))
+                .await?;
+        }
+
+        let confirm_token = Token::generate_reset_token();
+        let revert_token = Token::generate_reset_token();
+        let request = serde_json::to_string(&EmailChangeRequest {
+            user_id: id,
+            old_email: hashed_user.email.clone().unwrap(),
+            new_email: data.new_email.clone(),
+            confirm_token: confirm_token.clone(),
+        })?;
+
+        redis_con
+            .set_ex::<_, _, ()>(
+                format!("email-change:{confirm_token}"),
+                &request,
+                EMAIL_CHANGE_TTL_SECS,
             )
-            .execute(&db.pool)
             .await?;
-            Ok(())
-        } else {
-            Err("A jelszó érvénytelen".into())
+        redis_con
+            .set_ex::<_, _, ()>(
+                format!("email-change-revert:{revert_token}"),
+                &request,
+                EMAIL_CHANGE_REVERT_TTL_SECS,
+            )
+            .await?;
+        redis_con
+            .set_ex::<_, _, ()>(&pending_key, &confirm_token, EMAIL_CHANGE_TTL_SECS)
+            .await?;
+
+        let locale = Self::get_locale(db, id).await?;
+        Email::send_email_change_confirmation(redis_con, locale, &data.new_email, &confirm_token)
+            .await?;
+        Email::send_email_change_notice(
+            redis_con,
+            locale,
+            &hashed_user.email.unwrap(),
+            &data.new_email,
+            &revert_token,
+        )
+        .await?;
+        Ok(())
+    }
+
+    // Swap the e-mail address once the new one is confirmed
+    pub(crate) async fn confirm_email_change(
+        db: &Database,
+        redis_con: &mut ConnectionManager,
+        confirm_token: &str,
+        audit: &AuditContext,
+    ) -> Result<(), AppError> {
+        let token_key = format!("email-change:{confirm_token}");
+        let Some(request) = redis_con.get::<_, Option<String>>(&token_key).await? else {
+            return Err(AppError::BadRequest("invalid_token"));
+        };
+        let request = serde_json::from_str::<EmailChangeRequest>(&request)?;
+
+        let is_exists = sqlx::query!("SELECT * FROM users WHERE email = ?", request.new_email)
+            .fetch_optional(&db.pool)
+            .await?;
+
+        if is_exists.is_some() {
+            return Err(AppError::Conflict("email_exists"));
         }
+
+        let result = sqlx::query!(
+            r#"
+            UPDATE users
+            SET email = ?, email_verified = TRUE
+            WHERE id = ? AND email = ?
+            "#,
+            request.new_email,
+            request.user_id,
+            request.old_email
+        )
+        .execute(&db.pool)
+        .await?;
+
+        if result.rows_affected() > 0 {
+            let audit = audit.with_actor(request.user_id);
+            let target = AuditTarget::User(request.user_id);
+            let changes = AuditLog::change("email", &request.old_email, &request.new_email);
+            AuditLog::record(db, &audit, AuditAction::EmailChanged, target, changes).await;
+        }
+
+        redis_con.del::<_, ()>(&token_key).await?;
+        redis_con
+            .del::<_, ()>(format!("email-change-pending:{}", request.user_id))
+            .await?;
+        Ok(())
+    }
+
+    // Cancel a pending e-mail change or restore the previous address
+    pub(crate) async fn revert_email_change(
+        db: &Database,
+        redis_con: &mut ConnectionManager,
+        revert_token: &str,
+        audit: &AuditContext,
+    ) -> Result<(), AppError> {
+        let token_key = format!("email-change-revert:{revert_token}");
+        let Some(request) = redis_con.get::<_, Option<String>>(&token_key).await? else {
+            return Err(AppError::BadRequest("invalid_token"));
+        };
+        let request = serde_json::from_str::<EmailChangeRequest>(&request)?;
+
+        redis_con
+            .del::<_, ()>(format!("email-change:{}", request.confirm_token))
+            .await?;
+
+        let is_exists = sqlx::query!(
+            "SELECT * FROM users WHERE email = ? AND id != ?",
+            request.old_email,
+            request.user_id
+        )
+        .fetch_optional(&db.pool)
+        .await?;
+
+        if is_exists.is_some() {
+            return Err(AppError::Conflict("email_exists"));
+        }
+
+        let result = sqlx::query!(
+            r#"
+            UPDATE users
+            SET email = ?, email_verified = TRUE
+            WHERE id = ? AND email = ?
+            "#,
+            request.old_email,
+            request.user_id,
+            request.new_email
+        )
+        .execute(&db.pool)
+        .await?;
+
+        if result.rows_affected() > 0 {
+            let audit = audit.with_actor(request.user_id);
+            let target = AuditTarget::User(request.user_id);
+            let changes = AuditLog::change("email", &request.new_email, &request.old_email);
+            AuditLog::record(
+                db,
+                &audit,
+                AuditAction::EmailChangeReverted,
+                target,
+                changes,
+            )
+            .await;
+        }
+
+        redis_con.del::<_, ()>(&token_key).await?;
+        Ok(())
     }
 
     // Change user's username
@@ -400,7 +796,8 @@ impl User {
         db: &Database,
         id: i32,
         data: ChangeUsernameJson,
-    ) -> Result<String, Box<dyn Error>> {
+        audit: &AuditContext,
+    ) -> Result<(), AppError> {
         let is_exists = sqlx::query!(
             r#"SELECT * FROM users WHERE username = ?"#,
             data.new_username,
@@ -409,9 +806,13 @@ impl User {
         .await?;
 
         if is_exists.is_some() {
-            return Err("Username is taken!".into());
+            return Err(AppError::Conflict("username_taken"));
         }
 
+        let old_username = sqlx::query_scalar!(r#"SELECT username FROM users WHERE id = ?"#, id)
+            .fetch_one(&db.pool)
+            .await?;
+
         let _ = sqlx::query!(
             r#"UPDATE users SET username = ? WHERE id = ?"#,
             data.new_username,
@@ -419,15 +820,21 @@ impl User {
         )
         .execute(&db.pool)
         .await?;
-        Ok("Username successfully changed!".into())
+
+        let changes = AuditLog::change("username", old_username, &data.new_username);
+        let target = AuditTarget::User(id);
+        AuditLog::record(db, audit, AuditAction::UsernameChanged, target, changes).await;
+        Ok(())
     }
 
-    // Check if user is an admin
-    pub(crate) async fn is_admin(db: &Database, id: i32) -> Result<bool, Box<dyn Error>> {
-        let user_role = sqlx::query_as!(User, r#"SELECT * FROM users WHERE id = ?"#, id)
-            .fetch_one(&db.pool)
-            .await?;
-        Ok(user_role.group == UserGroup::Admin)
+    // The role of the user, read fresh so a changed role applies right away
+    pub(crate) async fn get_role(db: &Database, id: i32) -> Result<Role, AppError> {
+        let role =
+            sqlx::query_scalar!(r#"SELECT role AS `role: Role` FROM users WHERE id = ?"#, id)
+                .fetch_optional(&db.pool)
+                .await?;
+
+        role.ok_or(AppError::NotFound("user_not_found"))
     }
 
     // Check if a record exists in a specified table
@@ -436,7 +843,7 @@ impl User {
         table: &str,
         column: &str,
         value: &str,
-    ) -> Result<bool, Box<dyn Error>> {
+    ) -> Result<bool, AppError> {
         let result = sqlx::query(&format!("SELECT * FROM {} WHERE {} = ?", table, column))
             .bind(value)
             .fetch_optional(&db.pool)
@@ -446,7 +853,181 @@ impl User {
     }
 
     // Check if a user exists by user ID
-    pub async fn is_user_exists(db: &Database, user_id: i32) -> Result<bool, Box<dyn Error>> {
+    pub async fn is_user_exists(db: &Database, user_id: i32) -> Result<bool, AppError> {
         Self::is_exists(db, "users", "id", user_id.to_string().as_str()).await
     }
+
+    // The language of the e-mails sent to the user
+    pub async fn get_locale(db: &Database, user_id: i32) -> Result<Locale, AppError> {
+        let user = sqlx::query!(r#"SELECT locale FROM users WHERE id = ?"#, user_id)
+            .fetch_optional(&db.pool)
+            .await?;
+
+        match user {
+            Some(user) => Ok(Locale::parse(&user.locale).unwrap_or_default()),
+            None => Err(AppError::NotFound("user_not_found")),
+        }
+    }
+
+    // Fail if the account was disabled by an admin
+    pub async fn ensure_enabled(db: &Database, user_id: i32) -> Result<(), AppError> {
+        let disabled = sqlx::query_scalar!(
+            r#"SELECT disabled AS `disabled: bool` FROM users WHERE id = ?"#,
+            user_id
+        )
+        .fetch_optional(&db.pool)
+        .await?;
+
+        match disabled {
+            Some(false) => Ok(()),
+            Some(true) => Err(AppError::Forbidden("account_disabled")),
+            None => Err(AppError::NotFound("user_not_found")),
+        }
+    }
+
+    // Search users by e-mail, username or name, a page at a time
+    pub async fn search(
+        db: &Database,
+        query: &str,
+        page: u32,
+        per_page: u32,
+    ) -> Result<UserSearchPage, AppError> {
+        let pattern = format!("%{}%", escape_like(query.trim()));
+        // In u64, a huge page number would overflow a u32
+        let offset = u64::from(page.saturating_sub(1)) * u64::from(per_page);
+
+        let users = sqlx::query_as!(
+            UserSummary,
+            r#"
+            SELECT users.id, username, email, first_name, last_name, role AS `role: Role`,
+                disabled AS `disabled: bool`, email_verified AS `email_verified: bool`
+            FROM users
+            JOIN user_info ON user_info.user_id = users.id
+            WHERE email LIKE ? OR username LIKE ? OR first_name LIKE ? OR last_name LIKE ?
+            ORDER BY users.id
+            LIMIT ? OFFSET ?
+            "#,
+            pattern,
+            pattern,
+            pattern,
+            pattern,
+            per_page,
+            offset
+        )
+        .fetch_all(&db.pool)
+        .await?;
+
+        let total = sqlx::query_scalar!(
+            r#"
+            SELECT COUNT(*)
+            FROM users
+            JOIN user_info ON user_info.user_id = users.id
+            WHERE email LIKE ? OR username LIKE ? OR first_name LIKE ? OR last_name LIKE ?
+            "#,
+            pattern,
+            pattern,
+            pattern,
+            pattern
+        )
+        .fetch_one(&db.pool)
+        .await?;
+
+        Ok(UserSearchPage {
+            users,
+            page,
+            per_page,
+            total,
+        })
+    }
+
+    pub async fn get_details(db: &Database, user_id: i32) -> Result<UserDetails, AppError> {
+        let info = Self::get_info(db, user_id).await?;
+        let user = sqlx::query!(
+            r#"SELECT role AS `role: Role`, disabled AS `disabled: bool` FROM users WHERE id = ?"#,
+            user_id
+        )
+        .fetch_one(&db.pool)
+        .await?;
+
+        Ok(UserDetails {
+            id: user_id,
+            role: user.role,
+            disabled: user.disabled,
+            info,
+        })
+    }
+
+    pub async fn set_role(
+        db: &Database,
+        user_id: i32,
+        role: Role,
+        audit: &AuditContext,
+    ) -> Result<(), AppError> {
+        let old_role = Self::get_role(db, user_id).await?;
+        sqlx::query!(r#"UPDATE users SET role = ? WHERE id = ?"#, role, user_id)
+            .execute(&db.pool)
+            .await?;
+
+        let changes = AuditLog::change("role", old_role, role);
+        let target = AuditTarget::User(user_id);
+        AuditLog::record(db, audit, AuditAction::RoleChanged, target, changes).await;
+        Ok(())
+    }
+
+    // Disable or enable the account, disabling also logs the user out everywhere
+    pub async fn set_disabled(
+        db: &Database,
+        redis_con: &mut ConnectionManager,
+        user_id: i32,
+        disabled: bool,
+        audit: &AuditContext,
+    ) -> Result<(), AppError> {
+        let result = sqlx::query!(
+            r#"UPDATE users SET disabled = ? WHERE id = ?"#,
+            disabled,
+            user_id
+        )
+        .execute(&db.pool)
+        .await?;
+
+        if result.rows_affected() == 0 && !Self::is_user_exists(db, user_id).await? {
+            return Err(AppError::NotFound("user_not_found"));
+        }
+        if disabled {
+            RefreshToken::revoke_all(redis_con, user_id as usize).await?;
+        }
+
+        let action = match disabled {
+            true => AuditAction::AccountDisabled,
+            false => AuditAction::AccountEnabled,
+        };
+        AuditLog::record(db, audit, action, AuditTarget::User(user_id), None).await;
+        Ok(())
+    }
+
+    // Send a password reset link on the user's behalf
+    pub async fn force_password_reset(
+        db: &Database,
+        redis_con: &mut ConnectionManager,
+        user_id: i32,
+        audit: &AuditContext,
+    ) -> Result<(), AppError> {
+        let info = Self::get_info(db, user_id).await?;
+        Self::send_password_reset(db, redis_con, user_id, &info.email).await?;
+
+        let target = AuditTarget::User(user_id);
+        AuditLog::record(db, audit, AuditAction::PasswordResetRequested, target, None).await;
+        Ok(())
+    }
+
+    pub async fn set_locale(db: &Database, user_id: i32, locale: Locale) -> Result<(), AppError> {
+        sqlx::query!(
+            r#"UPDATE users SET locale = ? WHERE id = ?"#,
+            locale.code(),
+            user_id
+        )
+        .execute(&db.pool)
+        .await?;
+        Ok(())
+    }
 }
@@ -1,12 +1,13 @@
-use std::error::Error;
-
 use serde::{Deserialize, Serialize};
 
-use super::cart::Cart;
+use super::audit_log::{AuditAction, AuditContext, AuditLog, AuditTarget};
+use super::cart_preview::CartPreview;
+use super::user::User;
 use crate::database::Database;
+use crate::error::AppError;
 
-#[derive(Debug, Serialize, Deserialize)]
-enum TransactionHistoryStatus {
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum TransactionHistoryStatus {
     InProgress,
     Shipping,
     Delivered,
@@ -23,6 +24,16 @@ impl From<&str> for TransactionHistoryStatus {
     }
 }
 
+impl TransactionHistoryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionHistoryStatus::InProgress => "InProgress",
+            TransactionHistoryStatus::Shipping => "Shipping",
+            TransactionHistoryStatus::Delivered => "Delivered",
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TransactionHistory {
     id: u64,
@@ -47,24 +58,27 @@ pub struct TransactionBooks {
 }
 
 impl TransactionHistory {
-    pub async fn create(
-        db: &Database,
-        user_id: i32,
-        status: &str,
-    ) -> Result<Self, Box<dyn Error>> {
+    pub async fn create(db: &Database, user_id: i32, status: &str) -> Result<Self, AppError> {
+        if !User::is_email_verified(db, user_id).await? {
+            return Err(AppError::Forbidden("email_not_verified"));
+        }
+
         // check if books in cart
-        let books_to_buy = Cart::get_cart(db, user_id).await?.books;
-        println!("{:?}", books_to_buy);
-        if books_to_buy.is_empty() {
-            return Err("A felhasználónak nincs terméke a kosárban".into());
+        let preview = CartPreview::build(db, user_id).await?;
+        if preview.lines.is_empty() {
+            return Err(AppError::BadRequest("cart_empty"));
+        }
+        // The customer has to see the new prices before paying them
+        if preview.prices_changed {
+            return Err(AppError::Conflict("cart_prices_changed"));
         }
 
         let purchase_date = chrono::Local::now().date_naive();
-
-        let mut price = 0;
-        for book in books_to_buy.iter() {
-            price += book.price * book.quantity
-        }
+        // The orders keep their total in an INT column
+        let price = i32::try_from(preview.grand_total).map_err(|_| {
+            let details = serde_json::json!({ "max": i32::MAX });
+            AppError::Unprocessable("cart_total_too_large", Some(details))
+        })?;
 
         let transaction = sqlx::query!(
             r#"INSERT INTO transaction_history(user_id, status, price, purchase_date) VALUES(?, ?, ?, ?)"#,
@@ -76,11 +90,11 @@ impl TransactionHistory {
         .execute(&db.pool)
         .await?;
 
-        for book in books_to_buy.iter() {
+        for line in preview.lines.iter() {
             sqlx::query!(r#"INSERT INTO transaction_books(transaction_history_id, book_id, quantity) VALUES(?, ?, ?)"#,
                 transaction.last_insert_id(),
-                book.id,
-                book.quantity
+                line.book_id,
+                line.quantity
             )
             .execute(&db.pool)
             .await?;
@@ -100,10 +114,7 @@ impl TransactionHistory {
         })
     }
 
-    pub async fn get_all(
-        db: &Database,
-        user_id: i32,
-    ) -> Result<Vec<TransactionHistory>, Box<dyn Error>> {
+    pub async fn get_all(db: &Database, user_id: i32) -> Result<Vec<TransactionHistory>, AppError> {
         let transaction_histories = sqlx::query!(
             r#"SELECT id, user_id, status, price, purchase_date FROM transaction_history WHERE user_id = ?"#,
             user_id
@@ -153,4 +164,35 @@ impl TransactionHistory {
 
         Ok(result)
     }
+    // Move an order to another status, used by the staff
+    pub async fn update_status(
+        db: &Database,
+        order_id: u64,
+        status: TransactionHistoryStatus,
+        audit: &AuditContext,
+    ) -> Result<(), AppError> {
+        let old_status = sqlx::query_scalar!(
+            r#"SELECT status FROM transaction_history WHERE id = ?"#,
+            order_id
+        )
+        .fetch_optional(&db.pool)
+        .await?;
+
+        let Some(old_status) = old_status else {
+            return Err(AppError::NotFound("order_not_found"));
+        };
+
+        sqlx::query!(
+            r#"UPDATE transaction_history SET status = ? WHERE id = ?"#,
+            status.as_str(),
+            order_id
+        )
+        .execute(&db.pool)
+        .await?;
+
+        let changes = AuditLog::change("status", old_status, status.as_str());
+        let target = AuditTarget::Order(order_id);
+        AuditLog::record(db, audit, AuditAction::OrderStatusChanged, target, changes).await;
+        Ok(())
+    }
 }
@@ -0,0 +1,331 @@
+use crate::{
+    database::Database,
+    error::AppError,
+    extractors::authentication_token::AuthenticationToken,
+    i18n::Locale,
+    middlewares::require_permission::RequirePermission,
+    models::{
+        audit_log::{AuditContext, AuditLog, AuditLogFilter},
+        cart::Cart,
+        role::{Permission, Role},
+        two_factor::TwoFactor,
+        user::User,
+        user_history::{TransactionHistory, TransactionHistoryStatus},
+    },
+    utils::{email::Email, mail_queue::MailQueue, mailer::Mailer},
+};
+use actix_web::{web, HttpResponse, Responder, Scope};
+use serde::Deserialize;
+
+pub fn admin_scope() -> Scope {
+    web::scope("/admin")
+        .service(
+            web::resource("/mails/test")
+                .wrap(RequirePermission(Permission::MailsManage))
+                .route(web::post().to(send_test_mail)),
+        )
+        .service(
+            web::resource("/mails/failed")
+                .wrap(RequirePermission(Permission::MailsManage))
+                .route(web::get().to(get_failed_mails)),
+        )
+        .service(
+            web::resource("/mails/failed/retry")
+                .wrap(RequirePermission(Permission::MailsManage))
+                .route(web::post().to(retry_all_failed_mails)),
+        )
+        .service(
+            web::resource("/mails/failed/{id}/retry")
+                .wrap(RequirePermission(Permission::MailsManage))
+                .route(web::post().to(retry_failed_mail)),
+        )
+        .service(
+            web::resource("/users")
+                .wrap(RequirePermission(Permission::UsersRead))
+                .route(web::get().to(search_users)),
+        )
+        .service(
+            web::resource("/users/{id}")
+                .wrap(RequirePermission(Permission::UsersRead))
+                .route(web::get().to(get_user)),
+        )
+        .service(
+            web::resource("/users/{id}/orders")
+                .wrap(RequirePermission(Permission::OrdersRead))
+                .route(web::get().to(get_user_orders)),
+        )
+        .service(
+            web::resource("/users/{id}/cart")
+                .wrap(RequirePermission(Permission::UsersRead))
+                .route(web::get().to(get_user_cart)),
+        )
+        .service(
+            web::resource("/users/{id}/role")
+                .wrap(RequirePermission(Permission::UsersWrite))
+                .route(web::put().to(set_user_role)),
+        )
+        .service(
+            web::resource("/users/{id}/disabled")
+                .wrap(RequirePermission(Permission::UsersWrite))
+                .route(web::put().to(set_user_disabled)),
+        )
+        .service(
+            web::resource("/users/{id}/reset-password")
+                .wrap(RequirePermission(Permission::UsersWrite))
+                .route(web::post().to(reset_user_password)),
+        )
+        .service(
+            web::resource("/users/{id}/2fa-required")
+                .wrap(RequirePermission(Permission::UsersWrite))
+                .route(web::put().to(set_user_two_factor_required)),
+        )
+        .service(
+            web::resource("/orders/{id}/status")
+                .wrap(RequirePermission(Permission::OrdersUpdateStatus))
+                .route(web::put().to(set_order_status)),
+        )
+        .service(
+            web::resource("/audit-log")
+                .wrap(RequirePermission(Permission::AuditRead))
+                .route(web::get().to(get_audit_log)),
+        )
+}
+
+// Check the mail settings, the test mail goes to the admin without the queue
+async fn send_test_mail(
+    db: web::Data<Database>,
+    mailer: web::Data<dyn Mailer>,
+    auth_token: AuthenticationToken,
+    locale: Locale,
+) -> impl Responder {
+    let email = match User::get_info(&db, auth_token.id as i32).await {
+        Ok(info) => info.email,
+        Err(e) => return HttpResponse::from_error(e),
+    };
+
+    match Email::send_test(mailer.into_inner(), locale, &email).await {
+        Ok(_) => HttpResponse::Ok().json(locale.t("mail_test_sent")),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+async fn get_failed_mails(db: web::Data<Database>) -> impl Responder {
+    let mut redis_con = db.redis.clone();
+
+    match MailQueue::get_failed(&mut redis_con).await {
+        Ok(mails) => HttpResponse::Ok().json(mails),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+async fn retry_failed_mail(
+    db: web::Data<Database>,
+    mail_id: web::Path<String>,
+    locale: Locale,
+) -> impl Responder {
+    let mut redis_con = db.redis.clone();
+
+    match MailQueue::retry_failed(&mut redis_con, &mail_id).await {
+        Ok(_) => HttpResponse::Ok().json(locale.t("mail_requeued")),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+async fn retry_all_failed_mails(db: web::Data<Database>, locale: Locale) -> impl Responder {
+    let mut redis_con = db.redis.clone();
+
+    match MailQueue::retry_all_failed(&mut redis_con).await {
+        Ok(count) => HttpResponse::Ok()
+            .json(locale.t_with("mails_requeued", &[("count", count.to_string())])),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct TwoFactorRequiredJson {
+    required: bool,
+}
+
+async fn set_user_two_factor_required(
+    db: web::Data<Database>,
+    auth_token: AuthenticationToken,
+    user_id: web::Path<i32>,
+    data: web::Json<TwoFactorRequiredJson>,
+    locale: Locale,
+    audit: AuditContext,
+) -> impl Responder {
+    let audit = audit.with_actor(auth_token.id as i32);
+
+    match TwoFactor::set_required(&db, user_id.into_inner(), data.required, &audit).await {
+        Ok(_) => HttpResponse::Ok().json(locale.t("updated")),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+const DEFAULT_PAGE_SIZE: u32 = 20;
+const MAX_PAGE_SIZE: u32 = 100;
+
+#[derive(Deserialize)]
+struct UserSearchQuery {
+    q: Option<String>,
+    page: Option<u32>,
+    per_page: Option<u32>,
+}
+
+async fn search_users(
+    db: web::Data<Database>,
+    query: web::Query<UserSearchQuery>,
+) -> impl Responder {
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query
+        .per_page
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    match User::search(&db, query.q.as_deref().unwrap_or(""), page, per_page).await {
+        Ok(users) => HttpResponse::Ok().json(users),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+async fn get_user(db: web::Data<Database>, user_id: web::Path<i32>) -> impl Responder {
+    match User::get_details(&db, user_id.into_inner()).await {
+        Ok(user) => HttpResponse::Ok().json(user),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+async fn get_user_orders(db: web::Data<Database>, user_id: web::Path<i32>) -> impl Responder {
+    match TransactionHistory::get_all(&db, user_id.into_inner()).await {
+        Ok(orders) => HttpResponse::Ok().json(orders),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+async fn get_user_cart(db: web::Data<Database>, user_id: web::Path<i32>) -> impl Responder {
+    match Cart::get_cart(&db, user_id.into_inner()).await {
+        Ok(cart) => HttpResponse::Ok().json(cart),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+// Admins can't lock themselves out by demoting or disabling their own account
+fn check_not_self(auth_token: &AuthenticationToken, user_id: i32) -> Result<(), AppError> {
+    match auth_token.id as i32 == user_id {
+        true => Err(AppError::Forbidden("cannot_modify_self")),
+        false => Ok(()),
+    }
+}
+
+#[derive(Deserialize)]
+struct UserRoleJson {
+    role: Role,
+}
+
+async fn set_user_role(
+    db: web::Data<Database>,
+    auth_token: AuthenticationToken,
+    user_id: web::Path<i32>,
+    data: web::Json<UserRoleJson>,
+    locale: Locale,
+    audit: AuditContext,
+) -> impl Responder {
+    let user_id = user_id.into_inner();
+    if let Err(e) = check_not_self(&auth_token, user_id) {
+        return HttpResponse::from_error(e);
+    }
+    let audit = audit.with_actor(auth_token.id as i32);
+
+    match User::set_role(&db, user_id, data.role, &audit).await {
+        Ok(_) => HttpResponse::Ok().json(locale.t("updated")),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct UserDisabledJson {
+    disabled: bool,
+}
+
+async fn set_user_disabled(
+    db: web::Data<Database>,
+    auth_token: AuthenticationToken,
+    user_id: web::Path<i32>,
+    data: web::Json<UserDisabledJson>,
+    locale: Locale,
+    audit: AuditContext,
+) -> impl Responder {
+    let user_id = user_id.into_inner();
+    if let Err(e) = check_not_self(&auth_token, user_id) {
+        return HttpResponse::from_error(e);
+    }
+    let audit = audit.with_actor(auth_token.id as i32);
+
+    let mut redis_con = db.redis.clone();
+
+    match User::set_disabled(&db, &mut redis_con, user_id, data.disabled, &audit).await {
+        Ok(_) => HttpResponse::Ok().json(locale.t("updated")),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+async fn reset_user_password(
+    db: web::Data<Database>,
+    auth_token: AuthenticationToken,
+    user_id: web::Path<i32>,
+    locale: Locale,
+    audit: AuditContext,
+) -> impl Responder {
+    let audit = audit.with_actor(auth_token.id as i32);
+    let mut redis_con = db.redis.clone();
+
+    match User::force_password_reset(&db, &mut redis_con, user_id.into_inner(), &audit).await {
+        Ok(_) => HttpResponse::Ok().json(locale.t("password_reset_link_sent")),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct OrderStatusJson {
+    status: TransactionHistoryStatus,
+}
+
+async fn set_order_status(
+    db: web::Data<Database>,
+    auth_token: AuthenticationToken,
+    order_id: web::Path<u64>,
+    data: web::Json<OrderStatusJson>,
+    locale: Locale,
+    audit: AuditContext,
+) -> impl Responder {
+    let audit = audit.with_actor(auth_token.id as i32);
+
+    match TransactionHistory::update_status(&db, order_id.into_inner(), data.status, &audit).await {
+        Ok(_) => HttpResponse::Ok().json(locale.t("updated")),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct PageQuery {
+    page: Option<u32>,
+    per_page: Option<u32>,
+}
+
+// The filters and the paging are read from the same query string
+async fn get_audit_log(
+    db: web::Data<Database>,
+    filter: web::Query<AuditLogFilter>,
+    query: web::Query<PageQuery>,
+) -> impl Responder {
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query
+        .per_page
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    match AuditLog::query(&db, &filter, page, per_page).await {
+        Ok(entries) => HttpResponse::Ok().json(entries),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
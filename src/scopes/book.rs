@@ -1,10 +1,21 @@
-use crate::{database::Database, models::book::Book};
+use crate::{
+    database::Database,
+    extractors::{authentication_token::AuthenticationToken, validated_json::ValidatedJson},
+    i18n::Locale,
+    middlewares::require_permission::RequirePermission,
+    models::{audit_log::AuditContext, book::Book, role::Permission},
+};
 use actix_web::{web, HttpResponse, Responder, Scope};
 use serde::Deserialize;
+use validator::Validate;
 
 pub fn book_scope() -> Scope {
     web::scope("/book")
-        .route("/create", web::post().to(create_book))
+        .service(
+            web::resource("/create")
+                .wrap(RequirePermission(Permission::BooksWrite))
+                .route(web::post().to(create_book)),
+        )
         .route("/get-all", web::get().to(get_books))
         .route("/get-best", web::get().to(get_best_sellers))
         .route("/get/{id}", web::get().to(get_book_by_id))
@@ -13,41 +24,54 @@ pub fn book_scope() -> Scope {
     // .route("/{id}", web::delete().to(delete_book))
 }
 
-async fn create_book(db: web::Data<Database>, book: web::Json<Book>) -> impl Responder {
-    match Book::create(&db, book.into_inner()).await {
-        Ok(_) => HttpResponse::Created().json("Book created"),
-        Err(e) => HttpResponse::InternalServerError().json(format!("Hiba történt: {}", e)),
+async fn create_book(
+    db: web::Data<Database>,
+    auth_token: AuthenticationToken,
+    book: ValidatedJson<Book>,
+    locale: Locale,
+    audit: AuditContext,
+) -> impl Responder {
+    let audit = audit.with_actor(auth_token.id as i32);
+
+    match Book::create(&db, book.into_inner(), &audit).await {
+        Ok(_) => HttpResponse::Created().json(locale.t("book_created")),
+        Err(e) => HttpResponse::from_error(e),
     }
 }
 
 async fn get_books(db: web::Data<Database>) -> impl Responder {
-    let books = Book::get_all(&db).await.unwrap();
-    HttpResponse::Ok().json(books)
+    match Book::get_all(&db).await {
+        Ok(books) => HttpResponse::Ok().json(books),
+        Err(e) => HttpResponse::from_error(e),
+    }
 }
 
 async fn get_best_sellers(db: web::Data<Database>) -> impl Responder {
-    let books = Book::get_best_sellers(&db).await.unwrap();
-    HttpResponse::Ok().json(books)
+    match Book::get_best_sellers(&db).await {
+        Ok(books) => HttpResponse::Ok().json(books),
+        Err(e) => HttpResponse::from_error(e),
+    }
 }
 
 async fn get_book_by_id(db: web::Data<Database>, book_id: web::Path<i32>) -> impl Responder {
     match Book::get_by_id(&db, book_id.into_inner()).await {
         Ok(book) => HttpResponse::Ok().json(book),
-        Err(e) => HttpResponse::InternalServerError().json(format!("Hiba történt: {}", e)),
+        Err(e) => HttpResponse::from_error(e),
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Validate)]
 struct FilterInfoJson {
+    #[validate(length(max = 100))]
     content: String,
 }
 
 async fn filter_by_param(
     db: web::Data<Database>,
-    data: web::Json<FilterInfoJson>,
+    data: ValidatedJson<FilterInfoJson>,
 ) -> impl Responder {
     match Book::filter_by(&db, &data.content).await {
         Ok(books) => HttpResponse::Ok().json(books),
-        Err(e) => HttpResponse::InternalServerError().json(format!("Hiba történt: {}", e)),
+        Err(e) => HttpResponse::from_error(e),
     }
 }
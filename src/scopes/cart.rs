@@ -1,60 +1,229 @@
 use crate::{
     database::Database,
-    extractors::authentication_token::AuthenticationToken,
-    models::{cart::Cart, user_history::TransactionHistory},
+    extractors::{
+        authentication_token::AuthenticationToken, guest_cart_token::GuestCartToken,
+        validated_json::ValidatedJson,
+    },
+    i18n::Locale,
+    models::{
+        cart::{Cart, CartItemChange},
+        cart_preview::CartPreview,
+        guest_cart::GuestCart,
+        user_history::TransactionHistory,
+    },
+    server::WebData,
 };
 use actix_web::{web, HttpResponse, Responder, Scope};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use validator::Validate;
 
 pub fn cart_scope() -> Scope {
     web::scope("/cart")
+        .route("/guest", web::post().to(create_guest_cart))
+        .route("/guest", web::get().to(get_guest_cart))
+        .route("/guest/book/", web::put().to(increment_guest_book_quantity))
+        .route(
+            "/guest/book/",
+            web::delete().to(decrease_guest_book_quantity),
+        )
+        .route("", web::patch().to(update_cart))
+        .route("/items/{book_id}", web::put().to(set_book_quantity))
+        .route("/items/{book_id}", web::delete().to(remove_book))
         .route("/{user_id}", web::delete().to(delete_user_cart))
         .route("/book/", web::put().to(increment_book_quantity))
         .route("/book/", web::delete().to(decrease_book_quantity))
+        .route("/preview", web::get().to(get_cart_preview))
+        .route("/preview/confirm", web::post().to(confirm_cart_prices))
         .route("/purchase", web::post().to(buy_user_cart))
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Validate)]
 struct BookCartRequest {
+    #[validate(range(min = 1))]
     book_id: i32,
 }
 
+#[derive(Deserialize, Validate)]
+struct BookQuantityRequest {
+    #[validate(range(min = 0, max = 999))]
+    quantity: i32,
+}
+
+#[derive(Deserialize, Validate)]
+struct UpdateCartRequest {
+    #[validate(length(min = 1, max = 100), nested)]
+    items: Vec<CartItemChange>,
+}
+
+// Every cart mutation below answers with the cart as it is after the change
+async fn cart_response(db: &Database, user_id: i32) -> HttpResponse {
+    match Cart::get_cart(db, user_id).await {
+        Ok(cart) => HttpResponse::Ok().json(cart),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+async fn set_book_quantity(
+    db: web::Data<Database>,
+    auth_token: AuthenticationToken,
+    book_id: web::Path<i32>,
+    data: ValidatedJson<BookQuantityRequest>,
+) -> impl Responder {
+    let user_id = auth_token.id as i32;
+    let change = CartItemChange {
+        book_id: book_id.into_inner(),
+        quantity: data.quantity,
+    };
+
+    match Cart::apply_changes(&db, user_id, &[change]).await {
+        Ok(_) => cart_response(&db, user_id).await,
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+async fn remove_book(
+    db: web::Data<Database>,
+    auth_token: AuthenticationToken,
+    book_id: web::Path<i32>,
+) -> impl Responder {
+    let user_id = auth_token.id as i32;
+
+    match Cart::remove_book(&db, user_id, book_id.into_inner()).await {
+        Ok(_) => cart_response(&db, user_id).await,
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+// Several quantities at once, if one of them is invalid none of them are applied
+async fn update_cart(
+    db: web::Data<Database>,
+    auth_token: AuthenticationToken,
+    data: ValidatedJson<UpdateCartRequest>,
+) -> impl Responder {
+    let user_id = auth_token.id as i32;
+
+    match Cart::apply_changes(&db, user_id, &data.items).await {
+        Ok(_) => cart_response(&db, user_id).await,
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
 async fn delete_user_cart(
     db: web::Data<Database>,
     auth_token: AuthenticationToken,
+    locale: Locale,
 ) -> impl Responder {
     match Cart::delete_cart(&db, auth_token.id as i32).await {
-        Ok(_) => HttpResponse::Ok().json("Kosár sikeresen törölve."),
-        Err(e) => HttpResponse::InternalServerError().json(format!("Hiba történt: {}", e)),
+        Ok(_) => HttpResponse::Ok().json(locale.t("cart_deleted")),
+        Err(e) => HttpResponse::from_error(e),
     }
 }
 
 async fn increment_book_quantity(
     db: web::Data<Database>,
     auth_token: AuthenticationToken,
-    data: web::Json<BookCartRequest>,
+    data: ValidatedJson<BookCartRequest>,
 ) -> impl Responder {
     match Cart::increment_book_quantity(&db, auth_token.id as i32, data.book_id).await {
         Ok(_) => HttpResponse::Ok().json(""),
-        Err(e) => HttpResponse::InternalServerError().json(format!("Hiba történt: {}", e)),
+        Err(e) => HttpResponse::from_error(e),
     }
 }
 
 async fn decrease_book_quantity(
     db: web::Data<Database>,
     auth_token: AuthenticationToken,
-    data: web::Json<BookCartRequest>,
+    data: ValidatedJson<BookCartRequest>,
 ) -> impl Responder {
     match Cart::decrease_book_quantity(&db, auth_token.id as i32, data.book_id).await {
         Ok(_) => HttpResponse::Ok().json(""),
-        Err(e) => HttpResponse::InternalServerError().json(format!("Hiba történt: {}", e)),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+#[derive(Serialize)]
+struct GuestCartResponse {
+    cart_token: String,
+}
+
+// Visitors get a cart before logging in, it is merged into theirs at the login
+async fn create_guest_cart(secret: web::Data<WebData>) -> impl Responder {
+    let (_, cart_token) = GuestCartToken::issue(&secret.cart_tokens);
+    HttpResponse::Created().json(GuestCartResponse { cart_token })
+}
+
+async fn get_guest_cart(db: web::Data<Database>, cart_token: GuestCartToken) -> impl Responder {
+    let mut redis_con = db.redis.clone();
+
+    match GuestCart::get_cart(&db, &mut redis_con, &cart_token.cart_id).await {
+        Ok(cart) => HttpResponse::Ok().json(cart),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+async fn increment_guest_book_quantity(
+    db: web::Data<Database>,
+    cart_token: GuestCartToken,
+    data: ValidatedJson<BookCartRequest>,
+) -> impl Responder {
+    let mut redis_con = db.redis.clone();
+
+    match GuestCart::increment_book_quantity(&db, &mut redis_con, &cart_token.cart_id, data.book_id)
+        .await
+    {
+        Ok(_) => HttpResponse::Ok().json(""),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+async fn decrease_guest_book_quantity(
+    db: web::Data<Database>,
+    cart_token: GuestCartToken,
+    data: ValidatedJson<BookCartRequest>,
+) -> impl Responder {
+    let mut redis_con = db.redis.clone();
+
+    match GuestCart::decrease_book_quantity(&mut redis_con, &cart_token.cart_id, data.book_id).await
+    {
+        Ok(_) => HttpResponse::Ok().json(""),
+        Err(e) => HttpResponse::from_error(e),
     }
 }
 
-async fn buy_user_cart(db: web::Data<Database>, auth_token: AuthenticationToken) -> impl Responder {
+// Totals of the cart and the lines whose price changed since they were added
+async fn get_cart_preview(
+    db: web::Data<Database>,
+    auth_token: AuthenticationToken,
+) -> impl Responder {
+    match CartPreview::build(&db, auth_token.id as i32).await {
+        Ok(preview) => HttpResponse::Ok().json(preview),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+// The purchase is refused until the changed prices are confirmed
+async fn confirm_cart_prices(
+    db: web::Data<Database>,
+    auth_token: AuthenticationToken,
+) -> impl Responder {
+    let user_id = auth_token.id as i32;
+
+    match CartPreview::confirm_prices(&db, user_id).await {
+        Ok(_) => match CartPreview::build(&db, user_id).await {
+            Ok(preview) => HttpResponse::Ok().json(preview),
+            Err(e) => HttpResponse::from_error(e),
+        },
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+async fn buy_user_cart(
+    db: web::Data<Database>,
+    auth_token: AuthenticationToken,
+    locale: Locale,
+) -> impl Responder {
     match TransactionHistory::create(&db, auth_token.id as i32, "InProgress").await {
-        Ok(_) => HttpResponse::Ok()
-            .json("Megkaptuk a rendelését, további információkért e-mailt küldünk Önnek."),
-        Err(e) => HttpResponse::InternalServerError().json(format!("Hiba történt: {}", e)),
+        Ok(_) => HttpResponse::Ok().json(locale.t("order_received")),
+        Err(e) => HttpResponse::from_error(e),
     }
 }
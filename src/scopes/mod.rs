@@ -1,3 +1,5 @@
+pub mod admin;
 pub mod book;
 pub mod cart;
 pub mod user;
+pub mod well_known;
@@ -1,26 +1,101 @@
 use crate::{
     database::Database,
-    extractors::authentication_token::AuthenticationToken,
+    error::AppError,
+    extractors::{
+        authentication_token::AuthenticationToken, guest_cart_token::GuestCartToken,
+        validated_json::ValidatedJson,
+    },
+    i18n::Locale,
+    middlewares::rate_limit::{RateLimit, RateLimitIdentity},
     models::{
+        account_deletion::AccountDeletion,
+        audit_log::AuditContext,
         cart::Cart,
-        user::{User, UserGroup},
+        data_export::{DataExport, ExportFormat},
+        guest_cart::GuestCart,
+        role::{Permission, Role},
+        two_factor::{LoginStep, TwoFactor},
+        user::User,
         user_history::TransactionHistory,
     },
     server::WebData,
-    utils::jwt::generate_jwt_token,
+    utils::{
+        jwt::{revoke_jti, JwtKeys},
+        refresh_token::{RefreshToken, SessionInfo},
+        validation::{
+            validate_locale, validate_phone_number, validate_postal_code, validate_username,
+        },
+    },
 };
-use actix_web::{web, HttpResponse, Responder, Scope};
+use actix_web::{http::header, web, FromRequest, HttpRequest, HttpResponse, Responder, Scope};
+use redis::aio::ConnectionManager;
 use serde::{Deserialize, Serialize};
+use validator::Validate;
 
 pub fn user_scope() -> Scope {
     web::scope("/user")
-        .route("/sign-in", web::post().to(sign_in))
-        .route("/sign-in/email", web::post().to(sign_in_with_email))
-        .route("/send-code/email", web::post().to(send_authentication_code))
+        .service(
+            web::resource("/sign-in")
+                .wrap(auth_rate_limit())
+                .route(web::post().to(sign_in)),
+        )
+        .service(
+            web::resource("/sign-in/email")
+                .wrap(auth_rate_limit())
+                .route(web::post().to(sign_in_with_email)),
+        )
+        .service(
+            web::resource("/sign-in/2fa")
+                .wrap(auth_rate_limit())
+                .route(web::post().to(sign_in_with_two_factor)),
+        )
+        .service(
+            web::resource("/refresh")
+                .wrap(auth_rate_limit())
+                .route(web::post().to(refresh_token)),
+        )
+        .route("/logout", web::post().to(logout))
+        .route("/logout-all", web::post().to(logout_all))
+        .route("/sessions", web::get().to(get_user_sessions))
+        .route("/sessions/{id}", web::delete().to(delete_user_session))
+        .route("/2fa", web::get().to(get_two_factor_status))
+        .route("/2fa", web::delete().to(disable_two_factor))
+        .route("/2fa/enroll", web::post().to(enroll_two_factor))
+        .route("/2fa/confirm", web::post().to(confirm_two_factor))
+        .service(
+            web::resource("/2fa/setup/enroll")
+                .wrap(auth_rate_limit())
+                .route(web::post().to(enroll_two_factor_with_setup_token)),
+        )
+        .service(
+            web::resource("/2fa/setup/confirm")
+                .wrap(auth_rate_limit())
+                .route(web::post().to(confirm_two_factor_with_setup_token)),
+        )
+        .route(
+            "/2fa/recovery-codes",
+            web::post().to(regenerate_recovery_codes),
+        )
+        .service(
+            web::resource("/send-code/email")
+                .wrap(email_rate_limit())
+                .route(web::post().to(send_authentication_code)),
+        )
         .route("/protected", web::get().to(protected_route))
-        .route("/sign-up", web::post().to(sign_up))
+        .service(
+            web::resource("/sign-up")
+                .wrap(email_rate_limit())
+                .route(web::post().to(sign_up)),
+        )
+        .route("/verify-email", web::get().to(verify_email))
+        .service(
+            web::resource("/verify-email/resend")
+                .wrap(email_rate_limit())
+                .route(web::post().to(resend_verification_email)),
+        )
         .route("/info", web::get().to(get_user_info))
         .route("/is-admin", web::get().to(is_user_admin))
+        .route("/permissions", web::get().to(get_user_permissions))
         .route(
             "/change/personal-information",
             web::put().to(change_user_personal_information),
@@ -29,111 +104,544 @@ pub fn user_scope() -> Scope {
             "/change/billing-information",
             web::put().to(change_user_billing_information),
         )
-        .route("/change/email", web::put().to(change_user_email))
+        .service(
+            web::resource("/change/email")
+                .wrap(email_rate_limit())
+                .route(web::put().to(change_user_email)),
+        )
+        .route(
+            "/change/email/confirm",
+            web::get().to(confirm_user_email_change),
+        )
+        .route(
+            "/change/email/revert",
+            web::get().to(revert_user_email_change),
+        )
         .route("/change/username", web::put().to(change_user_username))
+        .route("/change/locale", web::put().to(change_user_locale))
         .route("/change/password", web::put().to(change_password))
-        .route("/forgot-password", web::post().to(forgot_password))
+        .service(
+            web::resource("/forgot-password")
+                .wrap(email_rate_limit())
+                .route(web::post().to(forgot_password)),
+        )
         .route("/reset-password", web::post().to(reset_user_password))
         .route("/delete-account", web::delete().to(delete_user_account))
         .route("/cart", web::get().to(get_user_cart))
         .route("/history/get-all", web::get().to(get_user_history))
+        .route("/export", web::post().to(request_data_export))
+        .route("/export/download", web::get().to(download_data_export))
 }
 
-#[derive(Deserialize)]
+// Guessing credentials
+fn auth_rate_limit() -> RateLimit {
+    RateLimit::new("auth", 10, 60, RateLimitIdentity::Ip)
+}
+
+// Every request sends an e-mail
+fn email_rate_limit() -> RateLimit {
+    RateLimit::new("email", 5, 600, RateLimitIdentity::Ip)
+}
+
+#[derive(Deserialize, Validate)]
 struct UserInfoJson {
+    #[validate(email, length(max = 50))]
     email: Option<String>,
+    #[validate(length(min = 1, max = 50))]
     username: Option<String>,
+    #[validate(length(min = 1, max = 128))]
+    password: Option<String>,
+}
+
+#[derive(Deserialize, Validate)]
+struct SignUpJson {
+    #[validate(required, email, length(max = 50))]
+    email: Option<String>,
+    #[validate(required, custom(function = validate_username))]
+    username: Option<String>,
+    #[validate(required, length(min = 1, max = 128))]
     password: Option<String>,
 }
 
 #[derive(Serialize)]
 struct LoginResponse {
     token: String,
-    group: UserGroup,
+    refresh_token: String,
+    role: Role,
+    permissions: &'static [Permission],
+    account_deletion_cancelled: bool,
+    // The guest cart sent with the login couldn't be merged, it is kept to try again
+    guest_cart_merge_failed: bool,
+    // Only sent when the login finished a forced 2FA enrolment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recovery_codes: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+struct TwoFactorPendingResponse {
+    two_factor_required: bool,
+    two_factor_token: String,
+}
+
+#[derive(Serialize)]
+struct TwoFactorSetupResponse {
+    two_factor_setup_required: bool,
+    two_factor_setup_token: String,
 }
 
 async fn sign_in(
+    req: HttpRequest,
     db: web::Data<Database>,
-    data: web::Json<UserInfoJson>,
+    data: ValidatedJson<UserInfoJson>,
     secret: web::Data<WebData>,
+    audit: AuditContext,
 ) -> impl Responder {
     let user = User {
         id: None,
         email: None,
         username: data.username.clone(),
         password: data.password.clone(),
-        group: UserGroup::None,
+        role: Role::default(),
     };
 
-    match User::login_with_password(&db, user).await {
-        Ok(logged_in_user) => HttpResponse::Ok().json(LoginResponse {
-            token: generate_jwt_token(
-                logged_in_user.id.unwrap() as usize,
-                secret.auth_secret.clone(),
-            )
-            .await,
-            group: logged_in_user.group,
+    let mut redis_con = db.redis.clone();
+
+    let login_step = match User::login_with_password(&db, &mut redis_con, user, &audit).await {
+        Ok(login_step) => login_step,
+        Err(e) => return HttpResponse::from_error(e),
+    };
+
+    login_step_response(&req, &db, &mut redis_con, login_step, &secret.jwt_keys).await
+}
+
+async fn login_step_response(
+    req: &HttpRequest,
+    db: &Database,
+    redis_con: &mut ConnectionManager,
+    login_step: LoginStep,
+    jwt_keys: &JwtKeys,
+) -> HttpResponse {
+    match login_step {
+        LoginStep::Authenticated(logged_in_user) => {
+            login_response(req, db, redis_con, logged_in_user, None, jwt_keys).await
+        }
+        LoginStep::TwoFactorPending(two_factor_token) => {
+            HttpResponse::Ok().json(TwoFactorPendingResponse {
+                two_factor_required: true,
+                two_factor_token,
+            })
+        }
+        LoginStep::TwoFactorSetupRequired(two_factor_setup_token) => {
+            HttpResponse::Ok().json(TwoFactorSetupResponse {
+                two_factor_setup_required: true,
+                two_factor_setup_token,
+            })
+        }
+    }
+}
+
+async fn login_response(
+    req: &HttpRequest,
+    db: &Database,
+    redis_con: &mut ConnectionManager,
+    logged_in_user: User,
+    recovery_codes: Option<Vec<String>>,
+    jwt_keys: &JwtKeys,
+) -> HttpResponse {
+    let user_id = logged_in_user.id.unwrap();
+    let info = SessionInfo::from_request(req);
+
+    // Logging in during the grace period keeps the account
+    let audit = AuditContext::from_request(req).with_actor(user_id);
+    let account_deletion_cancelled = match AccountDeletion::cancel(db, user_id, &audit).await {
+        Ok(cancelled) => cancelled,
+        Err(e) => return HttpResponse::from_error(e),
+    };
+
+    // A cart filled before logging in is sent along with the login that finishes it
+    let mut guest_cart_merge_failed = false;
+    if let Ok(cart_token) = GuestCartToken::extract(req).await {
+        if let Err(e) = GuestCart::merge_into(db, redis_con, &cart_token.cart_id, user_id).await {
+            log::warn!("Vendégkosár összevonási hiba ({user_id}): {:?}", e);
+            guest_cart_merge_failed = true;
+        }
+    }
+
+    match RefreshToken::issue(redis_con, user_id as usize, info, jwt_keys).await {
+        Ok(tokens) => HttpResponse::Ok().json(LoginResponse {
+            token: tokens.access_token,
+            refresh_token: tokens.refresh_token,
+            role: logged_in_user.role,
+            permissions: logged_in_user.role.permissions(),
+            account_deletion_cancelled,
+            guest_cart_merge_failed,
+            recovery_codes,
         }),
-        Err(e) => HttpResponse::Unauthorized().json(format!("Hiba történt: {}", e)),
+        Err(e) => HttpResponse::from_error(e),
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Validate)]
 struct EmailAuthJson {
+    #[validate(email, length(max = 50))]
+    pub email: String,
+    #[validate(length(equal = 6))]
     pub code: String,
 }
 
 async fn sign_in_with_email(
+    req: HttpRequest,
+    db: web::Data<Database>,
+    data: ValidatedJson<EmailAuthJson>,
+    secret: web::Data<WebData>,
+    audit: AuditContext,
+) -> impl Responder {
+    let mut redis_con = db.redis.clone();
+
+    match User::login_with_email(&db, &mut redis_con, &data.email, &data.code, &audit).await {
+        Ok(login_step) => {
+            login_step_response(&req, &db, &mut redis_con, login_step, &secret.jwt_keys).await
+        }
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+#[derive(Deserialize, Validate)]
+struct TwoFactorLoginJson {
+    #[validate(length(min = 1, max = 64))]
+    two_factor_token: String,
+    #[validate(length(min = 6, max = 11))]
+    code: String,
+}
+
+async fn sign_in_with_two_factor(
+    req: HttpRequest,
+    db: web::Data<Database>,
+    data: ValidatedJson<TwoFactorLoginJson>,
+    secret: web::Data<WebData>,
+    audit: AuditContext,
+) -> impl Responder {
+    let mut redis_con = db.redis.clone();
+    let token = &data.two_factor_token;
+
+    match TwoFactor::complete_login(&db, &mut redis_con, token, &data.code, &audit).await {
+        Ok(logged_in_user) => {
+            let jwt_keys = &secret.jwt_keys;
+            login_response(&req, &db, &mut redis_con, logged_in_user, None, jwt_keys).await
+        }
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+#[derive(Deserialize, Validate)]
+struct TwoFactorSetupJson {
+    #[validate(length(min = 1, max = 64))]
+    two_factor_setup_token: String,
+}
+
+async fn enroll_two_factor_with_setup_token(
+    db: web::Data<Database>,
+    data: ValidatedJson<TwoFactorSetupJson>,
+) -> impl Responder {
+    let mut redis_con = db.redis.clone();
+    let token = &data.two_factor_setup_token;
+
+    match TwoFactor::enroll_with_setup_token(&db, &mut redis_con, token).await {
+        Ok(enrollment) => HttpResponse::Ok().json(enrollment),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+#[derive(Deserialize, Validate)]
+struct TwoFactorSetupConfirmJson {
+    #[validate(length(min = 1, max = 64))]
+    two_factor_setup_token: String,
+    #[validate(length(min = 6, max = 11))]
+    code: String,
+}
+
+// The login of a user forced to enrol only finishes with the first code from the app
+async fn confirm_two_factor_with_setup_token(
+    req: HttpRequest,
     db: web::Data<Database>,
-    data: web::Json<EmailAuthJson>,
+    data: ValidatedJson<TwoFactorSetupConfirmJson>,
     secret: web::Data<WebData>,
+    audit: AuditContext,
 ) -> impl Responder {
-    let mut redis_con = db.redis.get_connection().unwrap();
+    let mut redis_con = db.redis.clone();
+    let token = &data.two_factor_setup_token;
 
-    match User::login_with_email(&db, &mut redis_con, &data.code).await {
-        Ok(logged_in_user) => HttpResponse::Ok().json(LoginResponse {
-            token: generate_jwt_token(
-                logged_in_user.id.unwrap() as usize,
-                secret.auth_secret.clone(),
+    match TwoFactor::confirm_with_setup_token(&db, &mut redis_con, token, &data.code, &audit).await
+    {
+        Ok((logged_in_user, recovery_codes)) => {
+            let jwt_keys = &secret.jwt_keys;
+            let recovery_codes = Some(recovery_codes);
+            login_response(
+                &req,
+                &db,
+                &mut redis_con,
+                logged_in_user,
+                recovery_codes,
+                jwt_keys,
             )
-            .await,
-            group: logged_in_user.group,
-        }),
-        Err(e) => HttpResponse::Unauthorized().json(format!("Hiba történt: {}", e)),
+            .await
+        }
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+#[derive(Deserialize, Validate)]
+struct RefreshTokenJson {
+    #[validate(length(min = 1, max = 255))]
+    refresh_token: String,
+}
+
+async fn refresh_token(
+    db: web::Data<Database>,
+    data: ValidatedJson<RefreshTokenJson>,
+    secret: web::Data<WebData>,
+) -> impl Responder {
+    let mut redis_con = db.redis.clone();
+
+    match RefreshToken::rotate(&mut redis_con, &data.refresh_token, &secret.jwt_keys).await {
+        Ok(tokens) => HttpResponse::Ok().json(tokens),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+#[derive(Deserialize, Validate)]
+struct LogoutJson {
+    #[validate(length(min = 1, max = 255))]
+    refresh_token: Option<String>,
+}
+
+async fn logout(
+    db: web::Data<Database>,
+    auth_token: AuthenticationToken,
+    data: ValidatedJson<LogoutJson>,
+    locale: Locale,
+) -> impl Responder {
+    let mut redis_con = db.redis.clone();
+
+    if let Some(refresh_token) = &data.refresh_token {
+        if let Err(e) = RefreshToken::revoke(&mut redis_con, auth_token.id, refresh_token).await {
+            return HttpResponse::from_error(e);
+        }
+    }
+
+    match revoke_jti(&mut redis_con, &auth_token.jti, auth_token.exp).await {
+        Ok(_) => HttpResponse::Ok().json(locale.t("logout_success")),
+        Err(e) => HttpResponse::from_error(AppError::from(e)),
+    }
+}
+
+async fn logout_all(
+    db: web::Data<Database>,
+    auth_token: AuthenticationToken,
+    locale: Locale,
+) -> impl Responder {
+    let mut redis_con = db.redis.clone();
+
+    if let Err(e) = RefreshToken::revoke_all(&mut redis_con, auth_token.id).await {
+        return HttpResponse::from_error(e);
+    }
+
+    match revoke_jti(&mut redis_con, &auth_token.jti, auth_token.exp).await {
+        Ok(_) => HttpResponse::Ok().json(locale.t("logout_all_success")),
+        Err(e) => HttpResponse::from_error(AppError::from(e)),
+    }
+}
+
+async fn get_user_sessions(
+    db: web::Data<Database>,
+    auth_token: AuthenticationToken,
+) -> impl Responder {
+    let mut redis_con = db.redis.clone();
+
+    match RefreshToken::get_sessions(&mut redis_con, auth_token.id, &auth_token.sid).await {
+        Ok(sessions) => HttpResponse::Ok().json(sessions),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+async fn delete_user_session(
+    db: web::Data<Database>,
+    auth_token: AuthenticationToken,
+    session_id: web::Path<String>,
+    locale: Locale,
+) -> impl Responder {
+    let mut redis_con = db.redis.clone();
+
+    match RefreshToken::revoke_session(&mut redis_con, auth_token.id, &session_id).await {
+        Ok(_) => HttpResponse::Ok().json(locale.t("session_revoked")),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+async fn get_two_factor_status(
+    db: web::Data<Database>,
+    auth_token: AuthenticationToken,
+) -> impl Responder {
+    match TwoFactor::get_status(&db, auth_token.id as i32).await {
+        Ok(status) => HttpResponse::Ok().json(status),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+async fn enroll_two_factor(
+    db: web::Data<Database>,
+    auth_token: AuthenticationToken,
+) -> impl Responder {
+    match TwoFactor::enroll(&db, auth_token.id as i32).await {
+        Ok(enrollment) => HttpResponse::Ok().json(enrollment),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+#[derive(Deserialize, Validate)]
+struct TwoFactorCodeJson {
+    #[validate(length(min = 6, max = 11))]
+    code: String,
+}
+
+async fn confirm_two_factor(
+    db: web::Data<Database>,
+    auth_token: AuthenticationToken,
+    data: ValidatedJson<TwoFactorCodeJson>,
+) -> impl Responder {
+    let mut redis_con = db.redis.clone();
+
+    match TwoFactor::confirm(&db, &mut redis_con, auth_token.id as i32, &data.code).await {
+        Ok(recovery_codes) => HttpResponse::Ok().json(recovery_codes),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+async fn regenerate_recovery_codes(
+    db: web::Data<Database>,
+    auth_token: AuthenticationToken,
+    data: ValidatedJson<TwoFactorCodeJson>,
+) -> impl Responder {
+    let mut redis_con = db.redis.clone();
+
+    match TwoFactor::regenerate_recovery_codes(
+        &db,
+        &mut redis_con,
+        auth_token.id as i32,
+        &data.code,
+    )
+    .await
+    {
+        Ok(recovery_codes) => HttpResponse::Ok().json(recovery_codes),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+#[derive(Deserialize, Validate)]
+struct DisableTwoFactorJson {
+    #[validate(length(min = 1, max = 128))]
+    password: String,
+    #[validate(length(min = 6, max = 11))]
+    code: String,
+}
+
+async fn disable_two_factor(
+    db: web::Data<Database>,
+    auth_token: AuthenticationToken,
+    data: ValidatedJson<DisableTwoFactorJson>,
+    locale: Locale,
+) -> impl Responder {
+    let mut redis_con = db.redis.clone();
+
+    match TwoFactor::disable(
+        &db,
+        &mut redis_con,
+        auth_token.id as i32,
+        &data.password,
+        &data.code,
+    )
+    .await
+    {
+        Ok(_) => HttpResponse::Ok().json(locale.t("two_factor_disabled")),
+        Err(e) => HttpResponse::from_error(e),
     }
 }
 
 async fn send_authentication_code(
     db: web::Data<Database>,
-    data: web::Json<UserInfoJson>,
+    data: ValidatedJson<UserInfoJson>,
+    locale: Locale,
 ) -> impl Responder {
-    let mut redis_con = db.redis.get_connection().unwrap();
+    let mut redis_con = db.redis.clone();
 
     let user = User {
         id: None,
         email: data.email.clone(),
         username: None,
         password: None,
-        group: UserGroup::None,
+        role: Role::default(),
     };
 
     match User::send_authentication_code(&db, &mut redis_con, user).await {
-        Ok(_) => HttpResponse::Ok().json("Hitelesítési kód elküldve!"),
-        Err(e) => HttpResponse::InternalServerError().json(format!("Hiba történt: {}", e)),
+        Ok(_) => HttpResponse::Ok().json(locale.t("authentication_code_sent")),
+        Err(e) => HttpResponse::from_error(e),
     }
 }
 
-async fn sign_up(db: web::Data<Database>, data: web::Json<UserInfoJson>) -> impl Responder {
+async fn sign_up(
+    db: web::Data<Database>,
+    data: ValidatedJson<SignUpJson>,
+    locale: Locale,
+) -> impl Responder {
     let user = User {
         id: None,
         email: data.email.clone(),
         username: data.username.clone(),
         password: data.password.clone(),
-        group: UserGroup::User,
+        role: Role::default(),
     };
-    match User::new(&db, user).await {
-        Ok(_) => HttpResponse::Created().json("Sikeres regisztráció!"),
-        Err(e) => HttpResponse::InternalServerError().json(format!("Hiba történt: {}", e)),
+    let new_user = match User::new(&db, user, locale).await {
+        Ok(new_user) => new_user,
+        Err(e) => return HttpResponse::from_error(e),
+    };
+
+    // The account exists by now, the user can ask for the mail again if it couldn't be queued
+    let mut redis_con = db.redis.clone();
+    let user_id = new_user.id.unwrap();
+    if let Err(e) = User::send_verification_email(&db, &mut redis_con, user_id).await {
+        log::error!("Megerősítő e-mail hiba ({user_id}): {:?}", e);
+    }
+    HttpResponse::Created().json(locale.t("registration_success"))
+}
+
+#[derive(Deserialize)]
+struct VerifyEmailQuery {
+    token: String,
+}
+
+async fn verify_email(
+    db: web::Data<Database>,
+    query: web::Query<VerifyEmailQuery>,
+    locale: Locale,
+) -> impl Responder {
+    let mut redis_con = db.redis.clone();
+
+    match User::verify_email(&db, &mut redis_con, &query.token).await {
+        Ok(_) => HttpResponse::Ok().json(locale.t("email_verified")),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+async fn resend_verification_email(
+    db: web::Data<Database>,
+    auth_token: AuthenticationToken,
+    locale: Locale,
+) -> impl Responder {
+    let mut redis_con = db.redis.clone();
+
+    match User::send_verification_email(&db, &mut redis_con, auth_token.id as i32).await {
+        Ok(_) => HttpResponse::Ok().json(locale.t("verification_email_sent")),
+        Err(e) => HttpResponse::from_error(e),
     }
 }
 
@@ -142,9 +650,9 @@ struct ProtectedResponse {
     message: String,
 }
 
-async fn protected_route(_auth_token: AuthenticationToken) -> impl Responder {
+async fn protected_route(_auth_token: AuthenticationToken, locale: Locale) -> impl Responder {
     HttpResponse::Ok().json(ProtectedResponse {
-        message: "Sikeres hitelesítés".to_string(),
+        message: locale.t("authenticated"),
     })
 }
 
@@ -154,114 +662,270 @@ async fn get_user_history(
 ) -> impl Responder {
     match TransactionHistory::get_all(&db, auth_token.id as i32).await {
         Ok(user_history) => HttpResponse::Ok().json(user_history),
-        Err(e) => HttpResponse::InternalServerError().json(format!("Hiba történt: {}", e)),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct DataExportQuery {
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+// A POST, since it starts a job and sends an e-mail, prefetchers and embedded links can't trigger it
+async fn request_data_export(
+    db: web::Data<Database>,
+    auth_token: AuthenticationToken,
+    query: web::Query<DataExportQuery>,
+    secret: web::Data<WebData>,
+    locale: Locale,
+    audit: AuditContext,
+) -> impl Responder {
+    let user_id = auth_token.id as i32;
+    let mut redis_con = db.redis.clone();
+    let links = secret.export_links.clone();
+    let audit = audit.with_actor(user_id);
+
+    match DataExport::request(&db, &mut redis_con, user_id, query.format, links, &audit).await {
+        Ok(_) => HttpResponse::Accepted().json(locale.t("export_requested")),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct DataExportDownloadQuery {
+    id: String,
+    expires: i64,
+    signature: String,
+}
+
+// Opened from the e-mail, the signed link stands in for the access token
+async fn download_data_export(
+    db: web::Data<Database>,
+    query: web::Query<DataExportDownloadQuery>,
+    secret: web::Data<WebData>,
+) -> impl Responder {
+    let mut redis_con = db.redis.clone();
+    let links = &secret.export_links;
+
+    match DataExport::download(
+        &mut redis_con,
+        links,
+        &query.id,
+        query.expires,
+        &query.signature,
+    )
+    .await
+    {
+        Ok((format, data)) => HttpResponse::Ok()
+            .content_type(format.content_type())
+            .insert_header(header::ContentDisposition::attachment(format.file_name()))
+            .insert_header(header::CacheControl(vec![header::CacheDirective::NoStore]))
+            .body(data),
+        Err(e) => HttpResponse::from_error(e),
     }
 }
 
 async fn get_user_cart(db: web::Data<Database>, auth_token: AuthenticationToken) -> impl Responder {
     match Cart::get_cart(&db, auth_token.id as i32).await {
         Ok(cart) => HttpResponse::Ok().json(cart),
-        Err(e) => HttpResponse::InternalServerError().json(format!("Hiba történt: {}", e)),
+        Err(e) => HttpResponse::from_error(e),
     }
 }
 
 async fn get_user_info(db: web::Data<Database>, auth_token: AuthenticationToken) -> impl Responder {
     match User::get_info(&db, auth_token.id as i32).await {
         Ok(user_info) => HttpResponse::Ok().json(user_info),
-        Err(e) => HttpResponse::InternalServerError().json(format!("Hiba történt: {}", e)),
+        Err(e) => HttpResponse::from_error(e),
     }
 }
 
 async fn is_user_admin(db: web::Data<Database>, auth_token: AuthenticationToken) -> impl Responder {
-    match User::is_admin(&db, auth_token.id as i32).await {
-        Ok(is_admin) => HttpResponse::Ok().json(is_admin),
-        Err(e) => HttpResponse::InternalServerError().json(format!("Hiba történt: {}", e)),
+    match User::get_role(&db, auth_token.id as i32).await {
+        Ok(role) => HttpResponse::Ok().json(role == Role::Admin),
+        Err(e) => HttpResponse::from_error(e),
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize)]
+struct PermissionsResponse {
+    role: Role,
+    permissions: &'static [Permission],
+}
+
+async fn get_user_permissions(
+    db: web::Data<Database>,
+    auth_token: AuthenticationToken,
+) -> impl Responder {
+    match User::get_role(&db, auth_token.id as i32).await {
+        Ok(role) => HttpResponse::Ok().json(PermissionsResponse {
+            role,
+            permissions: role.permissions(),
+        }),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+#[derive(Deserialize, Validate)]
 pub struct ChangeEmailJson {
+    #[validate(email, length(max = 50))]
     pub new_email: String,
+    #[validate(length(min = 1, max = 128))]
     pub password: String,
 }
 
 async fn change_user_email(
     db: web::Data<Database>,
     auth_token: AuthenticationToken,
-    data: web::Json<ChangeEmailJson>,
+    data: ValidatedJson<ChangeEmailJson>,
+    locale: Locale,
 ) -> impl Responder {
-    match User::change_email(&db, auth_token.id as i32, data.into_inner()).await {
-        Ok(_) => HttpResponse::Ok().json("Email sikeresen módosítva!"),
-        Err(e) => HttpResponse::InternalServerError().json(format!("Hiba történt: {}", e)),
+    let mut redis_con = db.redis.clone();
+
+    match User::change_email(&db, &mut redis_con, auth_token.id as i32, data.into_inner()).await {
+        Ok(_) => HttpResponse::Ok().json(locale.t("email_change_requested")),
+        Err(e) => HttpResponse::from_error(e),
     }
 }
 
 #[derive(Deserialize)]
+struct EmailChangeTokenQuery {
+    token: String,
+}
+
+async fn confirm_user_email_change(
+    db: web::Data<Database>,
+    query: web::Query<EmailChangeTokenQuery>,
+    locale: Locale,
+    audit: AuditContext,
+) -> impl Responder {
+    let mut redis_con = db.redis.clone();
+
+    match User::confirm_email_change(&db, &mut redis_con, &query.token, &audit).await {
+        Ok(_) => HttpResponse::Ok().json(locale.t("email_changed")),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+async fn revert_user_email_change(
+    db: web::Data<Database>,
+    query: web::Query<EmailChangeTokenQuery>,
+    locale: Locale,
+    audit: AuditContext,
+) -> impl Responder {
+    let mut redis_con = db.redis.clone();
+
+    match User::revert_email_change(&db, &mut redis_con, &query.token, &audit).await {
+        Ok(_) => HttpResponse::Ok().json(locale.t("email_change_reverted")),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+#[derive(Deserialize, Validate)]
 pub struct ChangeUsernameJson {
+    #[validate(custom(function = validate_username))]
     pub new_username: String,
 }
 
 async fn change_user_username(
     db: web::Data<Database>,
     auth_token: AuthenticationToken,
-    data: web::Json<ChangeUsernameJson>,
+    data: ValidatedJson<ChangeUsernameJson>,
+    locale: Locale,
+    audit: AuditContext,
 ) -> impl Responder {
-    match User::change_username(&db, auth_token.id as i32, data.into_inner()).await {
-        Ok(_) => HttpResponse::Ok().json("Felhasználónév sikeresen módosítva!"),
-        Err(e) => HttpResponse::InternalServerError().json(format!("Hiba történt: {}", e)),
+    let user_id = auth_token.id as i32;
+    let audit = audit.with_actor(user_id);
+
+    match User::change_username(&db, user_id, data.into_inner(), &audit).await {
+        Ok(_) => HttpResponse::Ok().json(locale.t("username_changed")),
+        Err(e) => HttpResponse::from_error(e),
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Validate)]
+struct ChangeLocaleJson {
+    #[validate(custom(function = validate_locale))]
+    locale: String,
+}
+
+// The e-mails are sent in this language, the responses follow Accept-Language
+async fn change_user_locale(
+    db: web::Data<Database>,
+    auth_token: AuthenticationToken,
+    data: ValidatedJson<ChangeLocaleJson>,
+) -> impl Responder {
+    let locale = Locale::parse(&data.locale).unwrap_or_default();
+
+    match User::set_locale(&db, auth_token.id as i32, locale).await {
+        Ok(_) => HttpResponse::Ok().json(locale.t("updated")),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+#[derive(Deserialize, Validate)]
 pub struct ChangePersonalInformationJson {
+    #[validate(length(min = 1, max = 50))]
     pub first_name: String,
+    #[validate(length(min = 1, max = 50))]
     pub last_name: String,
+    #[validate(custom(function = validate_phone_number))]
     pub phone_number: String,
 }
 
 async fn change_user_personal_information(
     db: web::Data<Database>,
     auth_token: AuthenticationToken,
-    data: web::Json<ChangePersonalInformationJson>,
+    data: ValidatedJson<ChangePersonalInformationJson>,
+    locale: Locale,
 ) -> impl Responder {
     match User::change_personal_info(&db, auth_token.id as i32, data.into_inner()).await {
-        Ok(_) => HttpResponse::Ok().json("Sikeresen módosítva"),
-        Err(e) => HttpResponse::InternalServerError().json(format!("Hiba történt: {}", e)),
+        Ok(_) => HttpResponse::Ok().json(locale.t("updated")),
+        Err(e) => HttpResponse::from_error(e),
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Validate)]
 pub struct ChangeBillingInformationJson {
+    #[validate(length(min = 1, max = 100))]
     pub billing_address: String,
+    #[validate(length(min = 1, max = 50))]
     pub city: String,
+    #[validate(length(max = 50))]
     pub state_province: Option<String>,
+    #[validate(custom(function = validate_postal_code))]
     pub postal_code: String,
 }
 
 async fn change_user_billing_information(
     db: web::Data<Database>,
     auth_token: AuthenticationToken,
-    data: web::Json<ChangeBillingInformationJson>,
+    data: ValidatedJson<ChangeBillingInformationJson>,
+    locale: Locale,
 ) -> impl Responder {
     match User::change_billing_info(&db, auth_token.id as i32, data.into_inner()).await {
-        Ok(_) => HttpResponse::Ok().json("Sikeresen módosítva"),
-        Err(e) => HttpResponse::InternalServerError().json(format!("Hiba történt: {}", e)),
+        Ok(_) => HttpResponse::Ok().json(locale.t("updated")),
+        Err(e) => HttpResponse::from_error(e),
     }
 }
 
-async fn forgot_password(db: web::Data<Database>, data: web::Json<UserInfoJson>) -> impl Responder {
-    let mut redis_con = db.redis.get_connection().unwrap();
+async fn forgot_password(
+    db: web::Data<Database>,
+    data: ValidatedJson<UserInfoJson>,
+    locale: Locale,
+) -> impl Responder {
+    let mut redis_con = db.redis.clone();
 
     let user = User {
         id: None,
         email: data.email.clone(),
         username: None,
         password: None,
-        group: UserGroup::User,
+        role: Role::default(),
     };
     match User::forgot_password(&db, &mut redis_con, user).await {
-        Ok(_) => HttpResponse::Ok().json("A jelszó visszaállítási link elküldve!"),
-        Err(e) => HttpResponse::InternalServerError().json(format!("Hiba történt: {}", e)),
+        Ok(_) => HttpResponse::Ok().json(locale.t("password_reset_link_sent")),
+        Err(e) => HttpResponse::from_error(e),
     }
 }
 
@@ -270,61 +934,95 @@ struct ResetPasswordQuery {
     token: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Validate)]
 struct ResetPasswordJson {
+    #[validate(length(min = 1, max = 128))]
     password: String,
 }
 
 async fn reset_user_password(
     db: web::Data<Database>,
     query: web::Query<ResetPasswordQuery>,
-    data: web::Json<ResetPasswordJson>,
+    data: ValidatedJson<ResetPasswordJson>,
+    locale: Locale,
+    audit: AuditContext,
 ) -> impl Responder {
-    let mut redis_con = db.redis.get_connection().unwrap();
+    let mut redis_con = db.redis.clone();
 
     match User::reset_password(
         &db,
         &mut redis_con,
         query.token.to_string(),
         data.password.to_string(),
+        &audit,
     )
     .await
     {
-        Ok(_) => HttpResponse::Ok().json("Sikeresen megváltoztattad a jelszavad!"),
-        Err(e) => HttpResponse::InternalServerError().json(format!("Hiba történt: {}", e)),
+        Ok(_) => HttpResponse::Ok().json(locale.t("password_reset_success")),
+        Err(e) => HttpResponse::from_error(e),
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Validate)]
 struct ChangePasswordJson {
+    #[validate(length(min = 1, max = 128))]
     old_password: String,
+    #[validate(length(min = 1, max = 128))]
     new_password: String,
 }
 
 async fn change_password(
     db: web::Data<Database>,
     auth_token: AuthenticationToken,
-    data: web::Json<ChangePasswordJson>,
+    data: ValidatedJson<ChangePasswordJson>,
+    locale: Locale,
+    audit: AuditContext,
 ) -> impl Responder {
+    let user_id = auth_token.id as i32;
+
     match User::change_password(
         &db,
-        auth_token.id as i32,
+        user_id,
         data.old_password.clone(),
         data.new_password.clone(),
+        &audit.with_actor(user_id),
     )
     .await
     {
-        Ok(_) => HttpResponse::Ok().json("Jelszó sikeresen módosítva"),
-        Err(e) => HttpResponse::InternalServerError().json(format!("Hiba történt: {}", e)),
+        Ok(_) => HttpResponse::Ok().json(locale.t("password_changed")),
+        Err(e) => HttpResponse::from_error(e),
     }
 }
 
+#[derive(Deserialize, Validate)]
+struct DeleteAccountJson {
+    #[validate(length(min = 1, max = 128))]
+    password: String,
+}
+
 async fn delete_user_account(
     db: web::Data<Database>,
     auth_token: AuthenticationToken,
+    data: ValidatedJson<DeleteAccountJson>,
+    locale: Locale,
+    audit: AuditContext,
 ) -> impl Responder {
-    match User::delete_account(&db, auth_token.id as i32).await {
-        Ok(_) => HttpResponse::Ok().json("A fiók sikeresen törölve lett!"),
-        Err(e) => HttpResponse::InternalServerError().json(format!("Hiba történt: {}", e)),
+    let user_id = auth_token.id as i32;
+    let audit = audit.with_actor(user_id);
+    let mut redis_con = db.redis.clone();
+
+    let delete_at =
+        match AccountDeletion::schedule(&db, &mut redis_con, user_id, &data.password, &audit).await
+        {
+            Ok(delete_at) => delete_at,
+            Err(e) => return HttpResponse::from_error(e),
+        };
+
+    match revoke_jti(&mut redis_con, &auth_token.jti, auth_token.exp).await {
+        Ok(_) => HttpResponse::Ok().json(locale.t_with(
+            "account_deletion_scheduled",
+            &[("date", delete_at.format("%Y-%m-%d").to_string())],
+        )),
+        Err(e) => HttpResponse::from_error(AppError::from(e)),
     }
 }
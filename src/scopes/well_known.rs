@@ -0,0 +1,15 @@
+use crate::server::WebData;
+use actix_web::{http::header, web, HttpResponse, Responder, Scope};
+
+pub fn well_known_scope() -> Scope {
+    web::scope("/.well-known").route("/jwks.json", web::get().to(jwks))
+}
+
+// Public keys of the access tokens, cached briefly so a rotated key shows up soon
+async fn jwks(web_data: web::Data<WebData>) -> impl Responder {
+    HttpResponse::Ok()
+        .insert_header(header::CacheControl(vec![header::CacheDirective::MaxAge(
+            300,
+        )]))
+        .json(web_data.jwt_keys.jwks())
+}
@@ -1,5 +1,21 @@
 use crate::database::Database;
+use crate::error::AppError;
+use crate::extractors::guest_cart_token::CART_TOKEN_HEADER;
+use crate::middlewares::localize::Localize;
+use crate::middlewares::rate_limit::{
+    RateLimit, RateLimitIdentity, RATELIMIT_LIMIT, RATELIMIT_REMAINING, RATELIMIT_RESET,
+};
+use crate::models::{account_deletion::AccountDeletion, cart_preview::PricingRules};
 use crate::scopes;
+use crate::utils::{
+    client_ip::ClientIp,
+    credentials_hashing,
+    export_link::ExportLinks,
+    jwt::JwtKeys,
+    mail_queue::MailQueue,
+    mailer::{mailer_from_env, Mailer},
+    signing::Signer,
+};
 
 use actix_cors::Cors;
 use actix_web::{http, web};
@@ -8,7 +24,10 @@ use env_logger::Env;
 use std::env;
 
 pub struct WebData {
-    pub auth_secret: String,
+    pub jwt_keys: JwtKeys,
+    pub export_links: ExportLinks,
+    pub cart_tokens: Signer,
+    pub client_ip: ClientIp,
 }
 
 pub struct Server;
@@ -21,35 +40,70 @@ impl Server {
             env_logger::init_from_env(Env::default().default_filter_or("info"));
         }
 
-        let auth_secret = env::var("SECRET_AUTH_KEY").expect("SECRET_AUTH_KEY must be set");
         let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
         let redis_url = env::var("REDIS_URL").expect("REDIS_URL must be set");
 
         // Create the database
-        let db = Database::new(&database_url, &redis_url).await.unwrap();
+        let db = Database::new(&database_url, &redis_url)
+            .await
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+        // Load the signing keys once, every worker shares them
+        let web_data = web::Data::new(WebData {
+            jwt_keys: JwtKeys::from_env().expect("Invalid JWT key configuration"),
+            export_links: ExportLinks::from_env().expect("Invalid export link configuration"),
+            cart_tokens: Signer::from_env("GUEST_CART_SECRET", "guest-cart")
+                .expect("Invalid guest cart configuration"),
+            client_ip: ClientIp::from_env().expect("Invalid trusted proxy configuration"),
+        });
+        PricingRules::get().expect("Invalid cart pricing configuration");
+        credentials_hashing::check_config().expect("Invalid password hashing configuration");
+        RateLimit::check_env().expect("Invalid rate limit configuration");
+
+        // Create the mailer once, the background mail worker and the handlers share it
+        let mailer: web::Data<dyn Mailer> =
+            web::Data::from(mailer_from_env().expect("Invalid mail transport configuration"));
+        MailQueue::spawn_worker(db.redis_client.clone(), mailer.clone().into_inner());
+
+        // Anonymize the accounts whose deletion grace period is over
+        AccountDeletion::spawn_worker(db.clone());
 
         HttpServer::new(move || {
             let cors = Cors::default()
                 // .allowed_origin("https://libri-project.vercel.app")
                 .allow_any_origin()
-                .allowed_methods(vec!["GET", "POST", "PUT", "DELETE"])
+                .allowed_methods(vec!["GET", "POST", "PUT", "PATCH", "DELETE"])
                 .allowed_headers(vec![
                     http::header::AUTHORIZATION,
                     http::header::ACCEPT,
                     http::header::CONTENT_TYPE,
+                    http::header::HeaderName::from_static(CART_TOKEN_HEADER),
+                ])
+                .expose_headers(vec![
+                    http::header::RETRY_AFTER,
+                    http::header::HeaderName::from_static(RATELIMIT_LIMIT),
+                    http::header::HeaderName::from_static(RATELIMIT_REMAINING),
+                    http::header::HeaderName::from_static(RATELIMIT_RESET),
                 ])
                 .max_age(3600);
 
             App::new()
+                .wrap(Localize)
+                .wrap(RateLimit::new("global", 300, 60, RateLimitIdentity::User))
                 .wrap(cors)
                 .wrap(Logger::default())
-                .app_data(web::Data::<Database>::new(db.clone()))
-                .app_data(web::Data::<WebData>::new(WebData {
-                    auth_secret: auth_secret.clone(),
+                .app_data(web::JsonConfig::default().error_handler(|e, _| {
+                    let details = serde_json::json!({ "reason": e.to_string() });
+                    AppError::Unprocessable("invalid_body", Some(details)).into()
                 }))
+                .app_data(web::Data::<Database>::new(db.clone()))
+                .app_data(web_data.clone())
+                .app_data(mailer.clone())
                 .service(scopes::user::user_scope())
                 .service(scopes::book::book_scope())
                 .service(scopes::cart::cart_scope())
+                .service(scopes::admin::admin_scope())
+                .service(scopes::well_known::well_known_scope())
         })
         .bind(("0.0.0.0", port))?
         .run()
@@ -0,0 +1,191 @@
+use actix_web::{http::header::HeaderName, web, HttpRequest};
+use std::env;
+use std::net::IpAddr;
+
+use crate::server::WebData;
+
+// A network in CIDR notation, e.g. `fdaa::/16`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IpNetwork {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl IpNetwork {
+    fn parse(network: &str) -> Result<Self, String> {
+        let (addr, prefix) = network.split_once('/').unwrap_or((network, ""));
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| format!("invalid trusted proxy network: {network}"))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            "" => max_prefix,
+            prefix => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .ok_or(format!("invalid trusted proxy network: {network}"))?,
+        };
+        Ok(Self { addr, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// Where the client address of a request is taken from. Headers like `X-Forwarded-For` can be set
+// by anyone, so by default only the address of the TCP peer is used
+//  - `TRUSTED_PROXY_HEADER` is the header the proxy puts the client address in, e.g. `Fly-Client-IP`
+//  - `TRUSTED_PROXIES` is the comma separated list of the proxy networks, the header is only read
+//    from requests coming from them
+#[derive(Debug, Clone, Default)]
+pub struct ClientIp {
+    header: Option<HeaderName>,
+    proxies: Vec<IpNetwork>,
+}
+
+impl ClientIp {
+    pub fn from_env() -> Result<Self, String> {
+        let Ok(header) = env::var("TRUSTED_PROXY_HEADER") else {
+            return Ok(Self::default());
+        };
+        let header = HeaderName::try_from(header.trim())
+            .map_err(|_| format!("invalid TRUSTED_PROXY_HEADER: {header}"))?;
+
+        let proxies = env::var("TRUSTED_PROXIES")
+            .map_err(|_| "TRUSTED_PROXIES must be set with TRUSTED_PROXY_HEADER".to_string())?
+            .split(',')
+            .map(|network| IpNetwork::parse(network.trim()))
+            .collect::<Result<Vec<IpNetwork>, String>>()?;
+
+        Ok(Self {
+            header: Some(header),
+            proxies,
+        })
+    }
+
+    // The address of the client who sent the request
+    pub fn of(req: &HttpRequest) -> String {
+        let peer = req.peer_addr().map(|addr| addr.ip());
+        match req.app_data::<web::Data<WebData>>() {
+            Some(web_data) => web_data.client_ip.resolve(req, peer),
+            None => peer.map(|ip| ip.to_string()).unwrap_or_default(),
+        }
+    }
+
+    fn resolve(&self, req: &HttpRequest, peer: Option<IpAddr>) -> String {
+        let forwarded = self
+            .header
+            .as_ref()
+            .and_then(|header| req.headers().get(header))
+            .and_then(|value| value.to_str().ok());
+        self.client_of(peer, forwarded)
+            .map(|ip| ip.to_string())
+            .unwrap_or_default()
+    }
+
+    // The proxy appends the address it saw, so only the last one of a list can be trusted
+    fn client_of(&self, peer: Option<IpAddr>, forwarded: Option<&str>) -> Option<IpAddr> {
+        let peer = peer?.to_canonical();
+        if !self.proxies.iter().any(|proxy| proxy.contains(peer)) {
+            return Some(peer);
+        }
+
+        forwarded
+            .and_then(|value| value.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse::<IpAddr>().ok())
+            .map(|ip| ip.to_canonical())
+            .or(Some(peer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn behind_proxy(networks: &[&str]) -> ClientIp {
+        ClientIp {
+            header: Some(HeaderName::from_static("fly-client-ip")),
+            proxies: networks
+                .iter()
+                .map(|network| IpNetwork::parse(network).unwrap())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn networks_match_their_prefix() {
+        let network = IpNetwork::parse("10.0.0.0/8").unwrap();
+        assert!(network.contains(ip("10.20.30.40")));
+        assert!(network.contains(ip("::ffff:10.20.30.40")));
+        assert!(!network.contains(ip("11.0.0.1")));
+
+        let network = IpNetwork::parse("fdaa::/16").unwrap();
+        assert!(network.contains(ip("fdaa:0:1::2")));
+        assert!(!network.contains(ip("fdab::1")));
+
+        assert!(IpNetwork::parse("192.0.2.1")
+            .unwrap()
+            .contains(ip("192.0.2.1")));
+        assert!(IpNetwork::parse("0.0.0.0/0")
+            .unwrap()
+            .contains(ip("203.0.113.9")));
+        assert!(IpNetwork::parse("10.0.0.0/33").is_err());
+        assert!(IpNetwork::parse("proxy/8").is_err());
+    }
+
+    #[test]
+    fn header_is_ignored_without_trusted_proxies() {
+        let client_ip = ClientIp::default();
+        assert_eq!(
+            client_ip.client_of(Some(ip("203.0.113.9")), Some("198.51.100.1")),
+            Some(ip("203.0.113.9"))
+        );
+        assert_eq!(client_ip.client_of(None, Some("198.51.100.1")), None);
+    }
+
+    #[test]
+    fn header_is_only_read_from_trusted_proxies() {
+        let client_ip = behind_proxy(&["fdaa::/16"]);
+        assert_eq!(
+            client_ip.client_of(Some(ip("fdaa::5")), Some("198.51.100.1")),
+            Some(ip("198.51.100.1"))
+        );
+        assert_eq!(
+            client_ip.client_of(Some(ip("203.0.113.9")), Some("198.51.100.1")),
+            Some(ip("203.0.113.9"))
+        );
+    }
+
+    #[test]
+    fn only_the_address_appended_by_the_proxy_is_used() {
+        let client_ip = behind_proxy(&["10.0.0.0/8"]);
+        assert_eq!(
+            client_ip.client_of(Some(ip("10.0.0.2")), Some("1.2.3.4, 198.51.100.1")),
+            Some(ip("198.51.100.1"))
+        );
+        assert_eq!(
+            client_ip.client_of(Some(ip("10.0.0.2")), Some("garbage")),
+            Some(ip("10.0.0.2"))
+        );
+        assert_eq!(
+            client_ip.client_of(Some(ip("10.0.0.2")), None),
+            Some(ip("10.0.0.2"))
+        );
+    }
+}
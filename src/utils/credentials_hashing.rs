@@ -1,20 +1,252 @@
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
-    Argon2,
+    Algorithm, Argon2, KeyId, Params, ParamsBuilder, Version,
 };
+use std::collections::HashMap;
+use std::env;
+use std::sync::OnceLock;
+
+const DEFAULT_PEPPER_ID: &str = "p1";
+
+// Argon2id with the parameters from the environment, read once at startup
+//  - `ARGON2_MEMORY_KIB`, `ARGON2_ITERATIONS`, `ARGON2_PARALLELISM` override the defaults
+//  - `PASSWORD_PEPPER` is an optional secret mixed into every new hash
+//  - `PASSWORD_PEPPER_ID` is stored in the hash, so it's known which pepper a hash was made with
+//  - `PASSWORD_OLD_PEPPERS` lists the rotated out peppers as `id:pepper,id:pepper`, hashes made
+//    with them still verify and are rehashed with the current one at the next login
+struct HashingConfig {
+    params: Params,
+    pepper: Option<(String, KeyId)>,
+    old_peppers: HashMap<Vec<u8>, String>,
+}
+
+fn parse_old_peppers(peppers: &str) -> Result<HashMap<Vec<u8>, String>, String> {
+    peppers
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| match entry.trim().split_once(':') {
+            Some((id, pepper)) if !id.is_empty() && !pepper.is_empty() => {
+                KeyId::try_from(id.as_bytes())
+                    .map_err(|_| format!("Invalid pepper id in PASSWORD_OLD_PEPPERS: {id}"))?;
+                Ok((id.as_bytes().to_vec(), pepper.to_string()))
+            }
+            _ => Err("PASSWORD_OLD_PEPPERS entries must be id:pepper".to_string()),
+        })
+        .collect()
+}
+
+impl HashingConfig {
+    fn from_env() -> Result<Self, String> {
+        let env_u32 = |name: &str, default: u32| match env::var(name) {
+            Ok(value) => value
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid {name}: {value}")),
+            Err(_) => Ok(default),
+        };
+
+        let pepper = match env::var("PASSWORD_PEPPER") {
+            Ok(pepper) if !pepper.is_empty() => {
+                let id = env::var("PASSWORD_PEPPER_ID").unwrap_or(DEFAULT_PEPPER_ID.to_string());
+                let key_id = KeyId::try_from(id.as_bytes())
+                    .map_err(|_| format!("Invalid PASSWORD_PEPPER_ID: {id}"))?;
+                Some((pepper, key_id))
+            }
+            _ => None,
+        };
+
+        let mut params = ParamsBuilder::new();
+        params
+            .m_cost(env_u32("ARGON2_MEMORY_KIB", Params::DEFAULT_M_COST)?)
+            .t_cost(env_u32("ARGON2_ITERATIONS", Params::DEFAULT_T_COST)?)
+            .p_cost(env_u32("ARGON2_PARALLELISM", Params::DEFAULT_P_COST)?);
+        if let Some((_, key_id)) = &pepper {
+            params.keyid(*key_id);
+        }
+        let params = params
+            .build()
+            .map_err(|e| format!("Invalid Argon2 parameters: {e}"))?;
+
+        let old_peppers = parse_old_peppers(&env::var("PASSWORD_OLD_PEPPERS").unwrap_or_default())?;
+
+        Ok(Self {
+            params,
+            pepper,
+            old_peppers,
+        })
+    }
+
+    // The current pepper or an old one, by the id stored in the hash
+    fn pepper_by_id(&self, key_id: &[u8]) -> Option<&str> {
+        match &self.pepper {
+            Some((pepper, current_id)) if key_id == current_id.as_bytes() => Some(pepper.as_str()),
+            _ => self.old_peppers.get(key_id).map(|pepper| pepper.as_str()),
+        }
+    }
+
+    fn hash_password(&self, password: &str) -> String {
+        let pepper = self.pepper.as_ref().map(|(pepper, _)| pepper.as_str());
+        let salt = SaltString::generate(&mut OsRng);
+        argon2(pepper, self.params.clone())
+            .hash_password(password.as_bytes(), &salt)
+            .unwrap()
+            .to_string()
+    }
+
+    fn verify_password(&self, password: &str, hashed_password: &str) -> bool {
+        let Ok(parsed_hash) = PasswordHash::new(hashed_password) else {
+            log::error!("Hibás jelszó hash az adatbázisban");
+            return false;
+        };
+        let Ok(params) = Params::try_from(&parsed_hash) else {
+            log::error!("Hibás Argon2 paraméterek az adatbázisban");
+            return false;
+        };
+
+        let pepper = match params.keyid() {
+            [] => None,
+            key_id => match self.pepper_by_id(key_id) {
+                Some(pepper) => Some(pepper),
+                None => return false,
+            },
+        };
+
+        argon2(pepper, params)
+            .verify_password(password.as_bytes(), &parsed_hash)
+            .is_ok()
+    }
+
+    fn needs_rehash(&self, hashed_password: &str) -> bool {
+        let Ok(parsed_hash) = PasswordHash::new(hashed_password) else {
+            return true;
+        };
+        if parsed_hash.algorithm != Algorithm::Argon2id.ident()
+            || parsed_hash.version != Some(Version::V0x13.into())
+        {
+            return true;
+        }
+
+        let current = &self.params;
+        match Params::try_from(&parsed_hash) {
+            Ok(params) => {
+                params.m_cost() != current.m_cost()
+                    || params.t_cost() != current.t_cost()
+                    || params.p_cost() != current.p_cost()
+                    || params.keyid() != current.keyid()
+            }
+            Err(_) => true,
+        }
+    }
+}
+
+fn load_config() -> &'static Result<HashingConfig, String> {
+    static CONFIG: OnceLock<Result<HashingConfig, String>> = OnceLock::new();
+    CONFIG.get_or_init(HashingConfig::from_env)
+}
+
+// Read once, `Server::run` checks it at startup so a bad value stops the server
+pub fn check_config() -> Result<(), String> {
+    load_config().as_ref().map(|_| ()).map_err(String::clone)
+}
+
+fn config() -> &'static HashingConfig {
+    load_config()
+        .as_ref()
+        .expect("Invalid password hashing configuration")
+}
+
+// The hasher for new hashes, or for checking a hash made with the given pepper
+fn argon2(pepper: Option<&str>, params: Params) -> Argon2<'_> {
+    match pepper {
+        Some(pepper) => Argon2::new_with_secret(
+            pepper.as_bytes(),
+            Algorithm::Argon2id,
+            Version::V0x13,
+            params,
+        )
+        .expect("Invalid PASSWORD_PEPPER"),
+        None => Argon2::new(Algorithm::Argon2id, Version::V0x13, params),
+    }
+}
 
 pub fn hash_password(password: &str) -> String {
-    let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
-    argon2
-        .hash_password(password.as_bytes(), &salt)
-        .unwrap()
-        .to_string()
+    config().hash_password(password)
 }
 
+// A malformed hash or one made with an unknown pepper never matches
 pub fn verify_password(password: &str, hashed_password: &str) -> bool {
-    let parsed_hash = PasswordHash::new(hashed_password).unwrap();
-    Argon2::default()
-        .verify_password(password.as_bytes(), &parsed_hash)
-        .is_ok()
+    config().verify_password(password, hashed_password)
+}
+
+// Check if a hash was made with other parameters or pepper than the current ones
+pub fn needs_rehash(hashed_password: &str) -> bool {
+    config().needs_rehash(hashed_password)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn old_peppers_are_read_by_id() {
+        let peppers = parse_old_peppers("p1:first, p2:sec:ond").unwrap();
+        assert_eq!(peppers.get("p1".as_bytes()).unwrap(), "first");
+        assert_eq!(peppers.get("p2".as_bytes()).unwrap(), "sec:ond");
+        assert!(parse_old_peppers("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_old_peppers_are_rejected() {
+        assert!(parse_old_peppers("p1").is_err());
+        assert!(parse_old_peppers(":pepper").is_err());
+        assert!(parse_old_peppers("p1:").is_err());
+    }
+
+    // Cheap parameters, the tests only care about which pepper and parameters a hash has
+    fn config_with(pepper: Option<(&str, &str)>, old_peppers: &str, t_cost: u32) -> HashingConfig {
+        let pepper = pepper
+            .map(|(id, pepper)| (pepper.to_string(), KeyId::try_from(id.as_bytes()).unwrap()));
+        let mut params = ParamsBuilder::new();
+        params.m_cost(Params::MIN_M_COST).t_cost(t_cost).p_cost(1);
+        if let Some((_, key_id)) = &pepper {
+            params.keyid(*key_id);
+        }
+        HashingConfig {
+            params: params.build().unwrap(),
+            pepper,
+            old_peppers: parse_old_peppers(old_peppers).unwrap(),
+        }
+    }
+
+    #[test]
+    fn hashes_with_other_parameters_or_pepper_need_a_rehash() {
+        let config = config_with(Some(("p2", "second")), "", 2);
+        let hash = config.hash_password("Tz8#kQ2v!mWp9rL");
+        assert!(!config.needs_rehash(&hash));
+
+        assert!(config_with(Some(("p2", "second")), "", 3).needs_rehash(&hash));
+        assert!(config_with(Some(("p3", "third")), "", 2).needs_rehash(&hash));
+        assert!(config_with(None, "", 2).needs_rehash(&hash));
+        assert!(config.needs_rehash("$2b$12$not.an.argon2.hash"));
+    }
+
+    #[test]
+    fn hash_made_with_an_old_pepper_verifies_and_is_rehashed_with_the_current_one() {
+        let old = config_with(Some(("p1", "first")), "", 2);
+        let hash = old.hash_password("Tz8#kQ2v!mWp9rL");
+
+        let current = config_with(Some(("p2", "second")), "p1:first", 2);
+        assert!(current.verify_password("Tz8#kQ2v!mWp9rL", &hash));
+        assert!(!current.verify_password("wrong password", &hash));
+        assert!(current.needs_rehash(&hash));
+
+        let rehashed = current.hash_password("Tz8#kQ2v!mWp9rL");
+        assert!(!current.needs_rehash(&rehashed));
+        assert!(current.verify_password("Tz8#kQ2v!mWp9rL", &rehashed));
+        assert!(!old.verify_password("Tz8#kQ2v!mWp9rL", &rehashed));
+
+        // Once the old pepper is dropped its hashes stop matching
+        let without_old = config_with(Some(("p2", "second")), "", 2);
+        assert!(!without_old.verify_password("Tz8#kQ2v!mWp9rL", &hash));
+    }
 }
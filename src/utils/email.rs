@@ -1,63 +1,149 @@
-use lettre::message::header::ContentType;
-use lettre::transport::smtp::authentication::Credentials;
-use lettre::{Message, SmtpTransport, Transport};
-use std::error::Error;
+use super::mail_queue::{MailJob, MailQueue};
+use super::mailer::Mailer;
+use crate::error::AppError;
+use crate::i18n::Locale;
+use redis::aio::ConnectionManager;
+use std::sync::Arc;
 
 pub struct Email;
 impl Email {
     pub async fn send_password_reset_email(
+        redis_con: &mut ConnectionManager,
+        locale: Locale,
         to: &str,
         reset_token: &str,
-    ) -> Result<(), Box<dyn Error>> {
-        let email = Message::builder()
-            .from("noreply@library-basement.com".parse().unwrap())
-            .to(to.parse().unwrap())
-            .subject("Jelszó visszaállítási kérelem")
-            .header(ContentType::TEXT_PLAIN)
-            .body(format!("\nA jelszó-visszaállítási kód a következő: {} \n Vagy a következő linkre kattintva: https://library-basement.vercel.app/reset-password?token={}.", reset_token, reset_token))
-            .unwrap();
-
-        let smtp_username = std::env::var("SMTP_USERNAME").expect("SMTP_USERNAME must be set");
-        let smtp_password = std::env::var("SMTP_PASSWORD").expect("SMTP_PASSWORD must be set");
-        let creds = Credentials::new(smtp_username, smtp_password);
-
-        let mailer = SmtpTransport::relay("smtp.gmail.com")
-            .unwrap()
-            .credentials(creds)
-            .build();
-
-        match mailer.send(&email) {
-            Ok(_) => println!("E-mail sikeresen elküldve!"),
-            Err(e) => eprintln!("Hiba történt: {:?}", e),
-        }
-
-        Ok(())
+    ) -> Result<(), AppError> {
+        let job = MailJob::new(
+            to,
+            &locale.t("email_password_reset_subject"),
+            locale.t_with(
+                "email_password_reset_body",
+                &[("token", reset_token.to_string())],
+            ),
+        );
+
+        MailQueue::enqueue(redis_con, &job).await
     }
 
-    pub async fn send_authentication_code(to: &str, code: &str) -> Result<(), Box<dyn Error>> {
-        let email = Message::builder()
-            .from("noreply@library-basement.com".parse().unwrap())
-            .to(to.parse().unwrap())
-            .subject("E-mail hitelesítési kód")
-            .header(ContentType::TEXT_PLAIN)
-            .body(format!("Az Ön e-mail hitelesítési kódja: {}", code))
-            .unwrap();
-
-        let smtp_username = std::env::var("SMTP_USERNAME").expect("SMTP_USERNAME must be set");
-        let smtp_password = std::env::var("SMTP_PASSWORD").expect("SMTP_PASSWORD must be set");
-        let creds = Credentials::new(smtp_username, smtp_password);
-
-        let mailer = SmtpTransport::relay("smtp.gmail.com")
-            .unwrap()
-            .credentials(creds)
-            .build();
-
-        match mailer.send(&email) {
-            Ok(_) => println!("E-mail sikeresen elküldve!"),
-            Err(e) => eprintln!("Hiba történt: {:?}", e),
-        }
-
-        Ok(())
+    pub async fn send_verification_email(
+        redis_con: &mut ConnectionManager,
+        locale: Locale,
+        to: &str,
+        verification_token: &str,
+    ) -> Result<(), AppError> {
+        let job = MailJob::new(
+            to,
+            &locale.t("email_verification_subject"),
+            locale.t_with(
+                "email_verification_body",
+                &[("token", verification_token.to_string())],
+            ),
+        );
+
+        MailQueue::enqueue(redis_con, &job).await
+    }
+
+    pub async fn send_email_change_confirmation(
+        redis_con: &mut ConnectionManager,
+        locale: Locale,
+        to: &str,
+        confirm_token: &str,
+    ) -> Result<(), AppError> {
+        let job = MailJob::new(
+            to,
+            &locale.t("email_change_confirmation_subject"),
+            locale.t_with(
+                "email_change_confirmation_body",
+                &[("token", confirm_token.to_string())],
+            ),
+        );
+
+        MailQueue::enqueue(redis_con, &job).await
+    }
+
+    pub async fn send_email_change_notice(
+        redis_con: &mut ConnectionManager,
+        locale: Locale,
+        to: &str,
+        new_email: &str,
+        revert_token: &str,
+    ) -> Result<(), AppError> {
+        let job = MailJob::new(
+            to,
+            &locale.t("email_change_notice_subject"),
+            locale.t_with(
+                "email_change_notice_body",
+                &[
+                    ("new_email", new_email.to_string()),
+                    ("token", revert_token.to_string()),
+                ],
+            ),
+        );
+
+        MailQueue::enqueue(redis_con, &job).await
+    }
+
+    pub async fn send_data_export_link(
+        redis_con: &mut ConnectionManager,
+        locale: Locale,
+        to: &str,
+        url: &str,
+    ) -> Result<(), AppError> {
+        let job = MailJob::new(
+            to,
+            &locale.t("email_data_export_subject"),
+            locale.t_with("email_data_export_body", &[("url", url.to_string())]),
+        );
+
+        MailQueue::enqueue(redis_con, &job).await
+    }
+
+    // Sent through the mailer directly, the error of the transport is returned
+    pub async fn send_test(
+        mailer: Arc<dyn Mailer>,
+        locale: Locale,
+        to: &str,
+    ) -> Result<(), AppError> {
+        let job = MailJob::new(
+            to,
+            &locale.t("email_test_subject"),
+            locale.t("email_test_body"),
+        );
+
+        MailQueue::send_now(mailer, job).await
+    }
+
+    pub async fn send_account_deletion_scheduled(
+        redis_con: &mut ConnectionManager,
+        locale: Locale,
+        to: &str,
+        date: &str,
+    ) -> Result<(), AppError> {
+        let job = MailJob::new(
+            to,
+            &locale.t("email_account_deletion_subject"),
+            locale.t_with("email_account_deletion_body", &[("date", date.to_string())]),
+        );
+
+        MailQueue::enqueue(redis_con, &job).await
+    }
+
+    pub async fn send_authentication_code(
+        redis_con: &mut ConnectionManager,
+        locale: Locale,
+        to: &str,
+        code: &str,
+    ) -> Result<(), AppError> {
+        let job = MailJob::new(
+            to,
+            &locale.t("email_authentication_code_subject"),
+            locale.t_with(
+                "email_authentication_code_body",
+                &[("code", code.to_string())],
+            ),
+        );
+
+        MailQueue::enqueue(redis_con, &job).await
     }
 }
 
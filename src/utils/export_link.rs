@@ -0,0 +1,81 @@
+use std::env;
+
+use super::signing::Signer;
+
+const DEFAULT_PUBLIC_API_URL: &str = "https://libri-backend.fly.dev";
+
+// Signed, time-limited links to the data exports, anyone holding a valid link can download it
+//  - `EXPORT_LINK_SECRET` is the signing key, `SECRET_AUTH_KEY` is used if it isn't set
+//  - `PUBLIC_API_URL` is where the API is reachable from the e-mails
+#[derive(Clone)]
+pub struct ExportLinks {
+    signer: Signer,
+    base_url: String,
+}
+
+impl ExportLinks {
+    pub fn from_env() -> Result<Self, String> {
+        let signer = Signer::from_env("EXPORT_LINK_SECRET", "export-link")?;
+        let base_url = env::var("PUBLIC_API_URL").unwrap_or(DEFAULT_PUBLIC_API_URL.to_string());
+
+        Ok(Self {
+            signer,
+            base_url: base_url.trim_end_matches('/').to_string(),
+        })
+    }
+
+    pub fn url(&self, export_id: &str, expires: i64) -> String {
+        let signature = self.signer.sign(&format!("{export_id}:{expires}"));
+        format!(
+            "{}/user/export/download?id={export_id}&expires={expires}&signature={signature}",
+            self.base_url
+        )
+    }
+
+    // An expired link is invalid even if it's signed
+    pub fn verify(&self, export_id: &str, expires: i64, signature: &str) -> bool {
+        expires > chrono::Utc::now().timestamp()
+            && self
+                .signer
+                .verify(&format!("{export_id}:{expires}"), signature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn links() -> ExportLinks {
+        ExportLinks {
+            signer: Signer::new("export-secret", "export-link"),
+            base_url: DEFAULT_PUBLIC_API_URL.to_string(),
+        }
+    }
+
+    fn signature_of(url: &str) -> &str {
+        url.rsplit_once("signature=").unwrap().1
+    }
+
+    #[test]
+    fn link_is_valid_until_it_expires() {
+        let links = links();
+        let expires = chrono::Utc::now().timestamp() + 60;
+        let url = links.url("export-1", expires);
+
+        assert!(url.starts_with(
+            "https://libri-backend.fly.dev/user/export/download?id=export-1&expires="
+        ));
+        assert!(links.verify("export-1", expires, signature_of(&url)));
+        assert!(!links.verify("export-2", expires, signature_of(&url)));
+        assert!(!links.verify("export-1", expires + 3600, signature_of(&url)));
+    }
+
+    #[test]
+    fn expired_link_is_rejected_even_if_signed() {
+        let links = links();
+        let expires = chrono::Utc::now().timestamp() - 1;
+        let url = links.url("export-1", expires);
+
+        assert!(!links.verify("export-1", expires, signature_of(&url)));
+    }
+}
@@ -1,41 +1,181 @@
-use actix_web::web;
-use jsonwebtoken::errors::Error as JwtError;
+use jsonwebtoken::errors::{Error as JwtError, ErrorKind};
+use jsonwebtoken::jwk::JwkSet;
 use jsonwebtoken::{
-    decode, encode, Algorithm, DecodingKey, EncodingKey, Header, TokenData, Validation,
+    decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation,
 };
-use serde::{Deserialize, Serialize};
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::{env, fs};
 
+use super::email::Token;
 use crate::extractors::authentication_token::Claims;
 
-pub async fn generate_jwt_token(id: usize, secret: String) -> String {
-    let exp: usize = (chrono::Utc::now() + chrono::Duration::hours(1)).timestamp() as usize;
-    let claims: Claims = Claims { id, exp };
-    let token = encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(secret.as_str().as_ref()),
-    )
-    .unwrap();
-    token
+const ACCESS_TOKEN_TTL_MINUTES: i64 = 15;
+const DEFAULT_ISSUER: &str = "library-basement";
+const DEFAULT_AUDIENCE: &str = "library-basement";
+const HMAC_KEY_ID: &str = "hs256";
+
+// The key new access tokens are signed with
+struct SigningKey {
+    kid: String,
+    algorithm: Algorithm,
+    key: EncodingKey,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
-pub struct DecodeBody {
-    pub token: String,
+// Keys of the access tokens, a token is accepted with any key of the set, so keys can be rotated
+//  - `JWT_JWKS_FILE` is a JWK set of the public keys, old keys stay in it until their tokens expire
+//  - `JWT_SIGNING_KID` is the kid of the current key, `JWT_PRIVATE_KEY_FILE` is its private key in PEM
+//  - without them tokens are signed with HS256 and `SECRET_AUTH_KEY`, and no key is published
+pub struct JwtKeys {
+    issuer: String,
+    audience: String,
+    signing: SigningKey,
+    verifying: HashMap<String, (Algorithm, DecodingKey)>,
+    jwks: JwkSet,
 }
 
-pub async fn _decode_id_from_token(
-    body: web::Json<DecodeBody>,
-    secret: String,
-) -> Result<usize, JwtError> {
-    let token_result: Result<TokenData<Claims>, JwtError> = decode::<Claims>(
-        &body.token,
-        &DecodingKey::from_secret(secret.as_str().as_ref()),
-        &Validation::new(Algorithm::HS256),
-    );
-
-    match token_result {
-        Ok(token) => Ok(token.claims.id),
-        Err(e) => Err(e),
+impl JwtKeys {
+    pub fn from_env() -> Result<Self, String> {
+        let issuer = env::var("JWT_ISSUER").unwrap_or(DEFAULT_ISSUER.to_string());
+        let audience = env::var("JWT_AUDIENCE").unwrap_or(DEFAULT_AUDIENCE.to_string());
+
+        let Ok(jwks_file) = env::var("JWT_JWKS_FILE") else {
+            let secret = env::var("SECRET_AUTH_KEY")
+                .map_err(|_| "SECRET_AUTH_KEY or JWT_JWKS_FILE must be set".to_string())?;
+            return Ok(Self {
+                issuer,
+                audience,
+                signing: SigningKey {
+                    kid: HMAC_KEY_ID.to_string(),
+                    algorithm: Algorithm::HS256,
+                    key: EncodingKey::from_secret(secret.as_bytes()),
+                },
+                verifying: HashMap::from([(
+                    HMAC_KEY_ID.to_string(),
+                    (
+                        Algorithm::HS256,
+                        DecodingKey::from_secret(secret.as_bytes()),
+                    ),
+                )]),
+                jwks: JwkSet { keys: Vec::new() },
+            });
+        };
+
+        // Only the public parameters of the keys are read, so the set is safe to publish as is
+        let jwks = fs::read_to_string(&jwks_file).map_err(|e| format!("{jwks_file}: {e}"))?;
+        let jwks =
+            serde_json::from_str::<JwkSet>(&jwks).map_err(|e| format!("{jwks_file}: {e}"))?;
+
+        let mut verifying = HashMap::new();
+        for jwk in jwks.keys.iter() {
+            let Some(kid) = jwk.common.key_id.clone() else {
+                return Err("Every key of the JWK set needs a kid".to_string());
+            };
+            let algorithm = jwk
+                .common
+                .key_algorithm
+                .and_then(|algorithm| Algorithm::from_str(&algorithm.to_string()).ok())
+                .filter(|algorithm| matches!(algorithm, Algorithm::RS256 | Algorithm::EdDSA))
+                .ok_or(format!("The key {kid} needs an RS256 or EdDSA alg"))?;
+            let key = DecodingKey::from_jwk(jwk).map_err(|e| format!("Invalid key {kid}: {e}"))?;
+            verifying.insert(kid, (algorithm, key));
+        }
+
+        let kid = env::var("JWT_SIGNING_KID").map_err(|_| "JWT_SIGNING_KID must be set")?;
+        let Some((algorithm, _)) = verifying.get(&kid) else {
+            return Err(format!("The signing key {kid} is missing from {jwks_file}"));
+        };
+        let private_key_file =
+            env::var("JWT_PRIVATE_KEY_FILE").map_err(|_| "JWT_PRIVATE_KEY_FILE must be set")?;
+        let pem = fs::read(&private_key_file).map_err(|e| format!("{private_key_file}: {e}"))?;
+        let key = match algorithm {
+            Algorithm::EdDSA => EncodingKey::from_ed_pem(&pem),
+            _ => EncodingKey::from_rsa_pem(&pem),
+        }
+        .map_err(|e| format!("{private_key_file}: {e}"))?;
+
+        let keys = Self {
+            issuer,
+            audience,
+            signing: SigningKey {
+                kid,
+                algorithm: *algorithm,
+                key,
+            },
+            verifying,
+            jwks,
+        };
+
+        // Fail at startup if the private key doesn't belong to the published one
+        let (token, _) = generate_jwt_token(0, "", &keys).map_err(|e| e.to_string())?;
+        let kid = &keys.signing.kid;
+        decode_jwt_token(&token, &keys)
+            .map_err(|_| format!("{private_key_file} doesn't match the key {kid}"))?;
+        Ok(keys)
     }
+
+    // The public keys for other services to verify our tokens with
+    pub fn jwks(&self) -> &JwkSet {
+        &self.jwks
+    }
+}
+
+pub fn generate_jwt_token(
+    id: usize,
+    session_id: &str,
+    keys: &JwtKeys,
+) -> Result<(String, Claims), JwtError> {
+    let now = chrono::Utc::now();
+    let exp: usize =
+        (now + chrono::Duration::minutes(ACCESS_TOKEN_TTL_MINUTES)).timestamp() as usize;
+    let claims: Claims = Claims {
+        iss: keys.issuer.clone(),
+        aud: keys.audience.clone(),
+        sub: id.to_string(),
+        iat: now.timestamp() as usize,
+        id,
+        exp,
+        jti: Token::generate_reset_token(),
+        sid: session_id.to_string(),
+    };
+    let header = Header {
+        kid: Some(keys.signing.kid.clone()),
+        ..Header::new(keys.signing.algorithm)
+    };
+    let token = encode(&header, &claims, &keys.signing.key)?;
+    Ok((token, claims))
+}
+
+// Check the signature with the key named in the header, and the standard claims
+pub fn decode_jwt_token(token: &str, keys: &JwtKeys) -> Result<Claims, JwtError> {
+    let header = decode_header(token)?;
+    let Some((algorithm, key)) = header.kid.and_then(|kid| keys.verifying.get(&kid)) else {
+        return Err(ErrorKind::InvalidToken.into());
+    };
+
+    let mut validation = Validation::new(*algorithm);
+    validation.set_issuer(&[&keys.issuer]);
+    validation.set_audience(&[&keys.audience]);
+    validation.set_required_spec_claims(&["exp", "iat", "iss", "aud", "sub"]);
+    decode::<Claims>(token, key, &validation).map(|token| token.claims)
+}
+
+// Deny an access token until it expires on its own
+pub async fn revoke_jti(
+    con: &mut ConnectionManager,
+    jti: &str,
+    exp: usize,
+) -> redis::RedisResult<()> {
+    let remaining = exp as i64 - chrono::Utc::now().timestamp();
+    if remaining > 0 {
+        con.set_ex::<_, _, ()>(format!("revoked-jti:{jti}"), 1, remaining as u64)
+            .await?;
+    }
+    Ok(())
+}
+
+pub async fn is_jti_revoked(con: &mut ConnectionManager, jti: &str) -> redis::RedisResult<bool> {
+    con.exists::<_, bool>(format!("revoked-jti:{jti}")).await
 }
@@ -0,0 +1,147 @@
+extern crate redis;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use std::net::{IpAddr, Ipv6Addr};
+
+use crate::error::AppError;
+
+const ACCOUNT_MAX_FAILURES: i64 = 5;
+// Higher, since many users can share an address behind a NAT
+const IP_MAX_FAILURES: i64 = 20;
+const FAILURE_WINDOW_SECS: i64 = 60 * 60 * 24;
+const BASE_LOCKOUT_SECS: i64 = 30;
+const MAX_LOCKOUT_SECS: i64 = 60 * 60;
+
+// The IP address has to be the trusted client address (`ClientIp`), a header the client can set
+// would give an attacker a fresh counter on every attempt
+pub struct LoginGuard;
+
+impl LoginGuard {
+    // Fail if the account or the IP address is locked out
+    pub async fn check(
+        con: &mut ConnectionManager,
+        user_id: Option<i32>,
+        ip: &str,
+    ) -> Result<(), AppError> {
+        for (subject, _) in Self::subjects(user_id, ip) {
+            let remaining = con.ttl::<_, i64>(format!("login-lock:{subject}")).await?;
+            if remaining > 0 {
+                return Err(AppError::TooManyRequests("login_locked", remaining));
+            }
+        }
+        Ok(())
+    }
+
+    // Count a failed attempt, every failure over the limit doubles the lockout
+    pub async fn record_failure(
+        con: &mut ConnectionManager,
+        user_id: Option<i32>,
+        ip: &str,
+    ) -> redis::RedisResult<()> {
+        for (subject, max_failures) in Self::subjects(user_id, ip) {
+            let failures_key = format!("login-failures:{subject}");
+            let failures = con.incr::<_, _, i64>(&failures_key, 1).await?;
+            con.expire::<_, ()>(&failures_key, FAILURE_WINDOW_SECS)
+                .await?;
+
+            if let Some(lockout) = Self::lockout_secs(failures, max_failures) {
+                con.set_ex::<_, _, ()>(format!("login-lock:{subject}"), 1, lockout as u64)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Forget the failures of the account after a successful login
+    pub async fn record_success(
+        con: &mut ConnectionManager,
+        user_id: i32,
+    ) -> redis::RedisResult<()> {
+        con.del::<_, ()>(format!("login-failures:user:{user_id}"))
+            .await?;
+        con.del::<_, ()>(format!("login-lock:user:{user_id}"))
+            .await?;
+        Ok(())
+    }
+
+    // No lockout below the limit, from there it starts at 30 seconds and doubles up to an hour
+    fn lockout_secs(failures: i64, max_failures: i64) -> Option<i64> {
+        if failures < max_failures {
+            return None;
+        }
+        let exponent = (failures - max_failures).min(16) as u32;
+        Some((BASE_LOCKOUT_SECS * 2_i64.pow(exponent)).min(MAX_LOCKOUT_SECS))
+    }
+
+    // An IPv6 client usually gets a whole /64, so its addresses are counted together
+    fn ip_subject(ip: &str) -> Option<String> {
+        match ip.parse::<IpAddr>().ok()? {
+            IpAddr::V4(ip) => Some(format!("ip:{ip}")),
+            IpAddr::V6(ip) => {
+                let network = u128::from(ip) & (u128::MAX << 64);
+                Some(format!("ip:{}/64", Ipv6Addr::from(network)))
+            }
+        }
+    }
+
+    // Without a known address only the account is counted, not everyone sharing the empty one
+    fn subjects(user_id: Option<i32>, ip: &str) -> Vec<(String, i64)> {
+        let mut subjects = Vec::new();
+        if let Some(ip) = Self::ip_subject(ip) {
+            subjects.push((ip, IP_MAX_FAILURES));
+        }
+        if let Some(user_id) = user_id {
+            subjects.push((format!("user:{user_id}"), ACCOUNT_MAX_FAILURES));
+        }
+        subjects
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lockout_doubles_from_the_limit_up_to_the_cap() {
+        assert_eq!(LoginGuard::lockout_secs(4, ACCOUNT_MAX_FAILURES), None);
+        assert_eq!(LoginGuard::lockout_secs(5, ACCOUNT_MAX_FAILURES), Some(30));
+        assert_eq!(LoginGuard::lockout_secs(6, ACCOUNT_MAX_FAILURES), Some(60));
+        assert_eq!(LoginGuard::lockout_secs(8, ACCOUNT_MAX_FAILURES), Some(240));
+        assert_eq!(
+            LoginGuard::lockout_secs(1000, ACCOUNT_MAX_FAILURES),
+            Some(MAX_LOCKOUT_SECS)
+        );
+    }
+
+    #[test]
+    fn ipv6_addresses_of_a_network_share_a_counter() {
+        assert_eq!(
+            LoginGuard::ip_subject("2001:db8:1:2:aaaa::1"),
+            LoginGuard::ip_subject("2001:db8:1:2:bbbb::2")
+        );
+        assert_ne!(
+            LoginGuard::ip_subject("2001:db8:1:2::1"),
+            LoginGuard::ip_subject("2001:db8:1:3::1")
+        );
+        assert_eq!(
+            LoginGuard::ip_subject("2001:db8:1:2::1").as_deref(),
+            Some("ip:2001:db8:1:2::/64")
+        );
+        assert_eq!(
+            LoginGuard::ip_subject("192.0.2.7").as_deref(),
+            Some("ip:192.0.2.7")
+        );
+    }
+
+    #[test]
+    fn unknown_address_only_counts_the_account() {
+        assert_eq!(
+            LoginGuard::subjects(Some(7), ""),
+            [("user:7".to_string(), ACCOUNT_MAX_FAILURES)]
+        );
+        assert_eq!(
+            LoginGuard::subjects(None, "192.0.2.7"),
+            [("ip:192.0.2.7".to_string(), IP_MAX_FAILURES)]
+        );
+    }
+}
@@ -0,0 +1,320 @@
+extern crate redis;
+use lettre::message::header::ContentType;
+use lettre::Message;
+use redis::aio::ConnectionManager;
+use redis::{AsyncCommands, Commands, Direction};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::error::AppError;
+
+use super::email::Token;
+use super::mailer::{Mailer, MailerError};
+
+const QUEUE_KEY: &str = "mail:queue";
+const SCHEDULED_KEY: &str = "mail:scheduled";
+const DEAD_KEY: &str = "mail:dead";
+// Every worker has its own processing list, and a lease it renews while it is running
+const WORKERS_KEY: &str = "mail:workers";
+const LEASE_SECS: u64 = 60 * 5;
+const RECLAIM_INTERVAL_SECS: u64 = 60;
+
+// Requeue a dead-lettered job, only the caller that removed it from the dead letters pushes it
+const RETRY_SCRIPT: &str = r"
+if redis.call('HDEL', KEYS[1], ARGV[1]) == 1 then
+    redis.call('LPUSH', KEYS[2], ARGV[2])
+    return 1
+end
+return 0
+";
+
+const MAX_ATTEMPTS: u32 = 5;
+const BASE_BACKOFF_SECS: i64 = 30;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MailJob {
+    pub id: String,
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub created_at: i64,
+}
+
+impl MailJob {
+    pub fn new(to: &str, subject: &str, body: String) -> Self {
+        Self {
+            id: Token::generate_reset_token(),
+            to: to.to_string(),
+            subject: subject.to_string(),
+            body,
+            attempts: 0,
+            last_error: None,
+            created_at: chrono::Utc::now().timestamp(),
+        }
+    }
+
+    fn to_message(&self) -> Result<Message, Box<dyn Error + Send + Sync>> {
+        let message = Message::builder()
+            .from("noreply@library-basement.com".parse().unwrap())
+            .to(self.to.parse()?)
+            .subject(self.subject.clone())
+            .header(ContentType::TEXT_PLAIN)
+            .body(self.body.clone())?;
+        Ok(message)
+    }
+
+    // Delay before the next attempt: 30s, 60s, 120s, ...
+    fn backoff_secs(&self) -> i64 {
+        BASE_BACKOFF_SECS * 2_i64.pow(self.attempts.saturating_sub(1))
+    }
+}
+
+pub struct MailQueue;
+
+impl MailQueue {
+    pub async fn enqueue(con: &mut ConnectionManager, job: &MailJob) -> Result<(), AppError> {
+        // Fail early on addresses the worker could never deliver to
+        job.to_message()
+            .map_err(|_| AppError::BadRequest("invalid_email"))?;
+
+        con.lpush::<_, _, ()>(QUEUE_KEY, serde_json::to_string(job)?)
+            .await?;
+        Ok(())
+    }
+
+    // Deliver right away instead of queueing, e.g. to check the transport settings
+    pub async fn send_now(mailer: Arc<dyn Mailer>, job: MailJob) -> Result<(), AppError> {
+        let message = job
+            .to_message()
+            .map_err(|_| AppError::BadRequest("invalid_email"))?;
+
+        let result = actix_web::web::block(move || mailer.send(&message))
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        result.map_err(|e| {
+            let details = serde_json::json!({ "reason": e.to_string() });
+            AppError::Unprocessable("mail_send_failed", Some(details))
+        })
+    }
+
+    pub async fn get_failed(con: &mut ConnectionManager) -> Result<Vec<MailJob>, AppError> {
+        let payloads = con.hvals::<_, Vec<String>>(DEAD_KEY).await?;
+
+        let mut jobs = payloads
+            .iter()
+            .map(|payload| serde_json::from_str::<MailJob>(payload))
+            .collect::<Result<Vec<MailJob>, _>>()?;
+        jobs.sort_by_key(|job| job.created_at);
+        Ok(jobs)
+    }
+
+    // Move a dead-lettered job back to the queue with a fresh attempt counter
+    pub async fn retry_failed(con: &mut ConnectionManager, id: &str) -> Result<(), AppError> {
+        let Some(payload) = con.hget::<_, _, Option<String>>(DEAD_KEY, id).await? else {
+            return Err(AppError::NotFound("mail_not_found"));
+        };
+
+        let mut job = serde_json::from_str::<MailJob>(&payload)?;
+        job.attempts = 0;
+        job.last_error = None;
+
+        let retried = redis::Script::new(RETRY_SCRIPT)
+            .key(DEAD_KEY)
+            .key(QUEUE_KEY)
+            .arg(id)
+            .arg(serde_json::to_string(&job)?)
+            .invoke_async::<i32>(con)
+            .await?;
+        if retried == 0 {
+            return Err(AppError::NotFound("mail_not_found"));
+        }
+        Ok(())
+    }
+
+    pub async fn retry_all_failed(con: &mut ConnectionManager) -> Result<usize, AppError> {
+        let jobs = Self::get_failed(con).await?;
+        let mut retried = 0;
+        for job in jobs.iter() {
+            match Self::retry_failed(con, &job.id).await {
+                Ok(_) => retried += 1,
+                // Retried by someone else in the meantime
+                Err(AppError::NotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(retried)
+    }
+
+    // Start the background worker delivering the queued e-mails
+    pub fn spawn_worker(client: redis::Client, mailer: Arc<dyn Mailer>) {
+        let worker_id = Token::generate_reset_token();
+        std::thread::spawn(move || loop {
+            let mut con = match client.get_connection() {
+                Ok(con) => con,
+                Err(e) => {
+                    log::warn!("Levélküldő: nem sikerült csatlakozni a Redishez: {:?}", e);
+                    std::thread::sleep(Duration::from_secs(5));
+                    continue;
+                }
+            };
+
+            if let Err(e) = Self::run_worker(&mut con, mailer.as_ref(), &worker_id) {
+                log::warn!("Levélküldő hiba: {:?}", e);
+                std::thread::sleep(Duration::from_secs(1));
+            }
+        });
+    }
+
+    fn processing_key(worker_id: &str) -> String {
+        format!("mail:processing:{worker_id}")
+    }
+
+    fn lease_key(worker_id: &str) -> String {
+        format!("mail:worker:{worker_id}")
+    }
+
+    fn run_worker(
+        con: &mut redis::Connection,
+        mailer: &dyn Mailer,
+        worker_id: &str,
+    ) -> redis::RedisResult<()> {
+        let processing_key = Self::processing_key(worker_id);
+        con.sadd::<_, _, ()>(WORKERS_KEY, worker_id)?;
+
+        let mut last_reclaim: Option<Instant> = None;
+        loop {
+            con.set_ex::<_, _, ()>(Self::lease_key(worker_id), 1, LEASE_SECS)?;
+            if last_reclaim.is_none_or(|at| at.elapsed().as_secs() >= RECLAIM_INTERVAL_SECS) {
+                Self::reclaim_expired(con)?;
+                last_reclaim = Some(Instant::now());
+            }
+            Self::promote_scheduled(con)?;
+
+            let payload = con.blmove::<_, _, Option<String>>(
+                QUEUE_KEY,
+                &processing_key,
+                Direction::Right,
+                Direction::Left,
+                1.0,
+            )?;
+
+            if let Some(payload) = payload {
+                Self::process(con, mailer, &payload)?;
+                con.lrem::<_, _, ()>(&processing_key, 1, &payload)?;
+            }
+        }
+    }
+
+    // Jobs of workers whose lease ran out were never confirmed, they are sent again. The jobs of
+    // the running workers are left alone, so a mail is never sent by two workers at once
+    fn reclaim_expired(con: &mut redis::Connection) -> redis::RedisResult<()> {
+        let worker_ids = con.smembers::<_, Vec<String>>(WORKERS_KEY)?;
+        for worker_id in worker_ids {
+            if con.exists::<_, bool>(Self::lease_key(&worker_id))? {
+                continue;
+            }
+
+            let processing_key = Self::processing_key(&worker_id);
+            while con
+                .rpoplpush::<_, _, Option<String>>(&processing_key, QUEUE_KEY)?
+                .is_some()
+            {}
+            con.srem::<_, _, ()>(WORKERS_KEY, &worker_id)?;
+        }
+        Ok(())
+    }
+
+    fn promote_scheduled(con: &mut redis::Connection) -> redis::RedisResult<()> {
+        let now = chrono::Utc::now().timestamp();
+        let due = con.zrangebyscore::<_, _, _, Vec<String>>(SCHEDULED_KEY, "-inf", now)?;
+
+        for payload in due {
+            // Only the worker that removed the entry may requeue it
+            if con.zrem::<_, _, i32>(SCHEDULED_KEY, &payload)? == 1 {
+                con.lpush::<_, _, ()>(QUEUE_KEY, &payload)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn process(
+        con: &mut redis::Connection,
+        mailer: &dyn Mailer,
+        payload: &str,
+    ) -> redis::RedisResult<()> {
+        let mut job = match serde_json::from_str::<MailJob>(payload) {
+            Ok(job) => job,
+            Err(e) => {
+                log::error!("Hibás levél a sorban, eldobva: {:?}", e);
+                return Ok(());
+            }
+        };
+
+        // A message that can't even be built fails the same way every time
+        let result = match job.to_message() {
+            Ok(message) => mailer.send(&message).map_err(|e| {
+                let permanent = Self::is_permanent(&e);
+                (e, permanent)
+            }),
+            Err(e) => Err((e, true)),
+        };
+        let Err((e, permanent)) = result else {
+            log::info!("E-mail elküldve ({})", job.id);
+            return Ok(());
+        };
+
+        job.attempts += 1;
+        job.last_error = Some(e.to_string());
+        let payload = serde_json::to_string(&job).unwrap();
+
+        if permanent || job.attempts >= MAX_ATTEMPTS {
+            log::error!("Az e-mail végleg sikertelen ({}): {:?}", job.id, e);
+            con.hset::<_, _, _, ()>(DEAD_KEY, &job.id, payload)?;
+        } else {
+            log::warn!("Sikertelen e-mail, újrapróbálás ({}): {:?}", job.id, e);
+            let retry_at = chrono::Utc::now().timestamp() + job.backoff_secs();
+            con.zadd::<_, _, _, ()>(SCHEDULED_KEY, payload, retry_at)?;
+        }
+        Ok(())
+    }
+
+    // A 5xx answer of the SMTP server, e.g. an unknown mailbox, would be the same on every retry
+    fn is_permanent(error: &MailerError) -> bool {
+        error
+            .downcast_ref::<lettre::transport::smtp::Error>()
+            .is_some_and(|error| error.is_permanent())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_address_is_a_permanent_failure() {
+        let job = MailJob::new("not an address", "Teszt", "Teszt levél".to_string());
+        assert!(job.to_message().is_err());
+    }
+
+    #[test]
+    fn only_smtp_rejections_are_permanent() {
+        let error: MailerError = "connection refused".into();
+        assert!(!MailQueue::is_permanent(&error));
+    }
+
+    #[test]
+    fn backoff_doubles_with_every_attempt() {
+        let mut job = MailJob::new("user@example.com", "Teszt", "Teszt levél".to_string());
+        let backoffs = (1..=4)
+            .map(|attempts| {
+                job.attempts = attempts;
+                job.backoff_secs()
+            })
+            .collect::<Vec<i64>>();
+        assert_eq!(backoffs, [30, 60, 120, 240]);
+    }
+}
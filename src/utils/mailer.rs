@@ -0,0 +1,188 @@
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{FileTransport, Message, SmtpTransport, Transport};
+use std::env;
+use std::error::Error;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+pub type MailerError = Box<dyn Error + Send + Sync>;
+
+// Anything that can deliver an already built e-mail
+pub trait Mailer: Send + Sync {
+    fn send(&self, email: &Message) -> Result<(), MailerError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmtpTls {
+    None,
+    StartTls,
+    Tls,
+}
+
+// A typo must not quietly pick another mode, the server doesn't start with an unknown one
+impl FromStr for SmtpTls {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(SmtpTls::None),
+            "starttls" => Ok(SmtpTls::StartTls),
+            "tls" => Ok(SmtpTls::Tls),
+            other => Err(format!("Unknown SMTP_TLS: {}", other)),
+        }
+    }
+}
+
+pub struct SmtpMailer {
+    transport: SmtpTransport,
+}
+
+impl SmtpMailer {
+    pub fn new(
+        host: &str,
+        port: Option<u16>,
+        tls: SmtpTls,
+        credentials: Option<Credentials>,
+    ) -> Result<Self, MailerError> {
+        let mut builder = match tls {
+            SmtpTls::None => SmtpTransport::builder_dangerous(host),
+            SmtpTls::StartTls => SmtpTransport::starttls_relay(host)?,
+            SmtpTls::Tls => SmtpTransport::relay(host)?,
+        };
+
+        if let Some(port) = port {
+            builder = builder.port(port);
+        }
+        if let Some(credentials) = credentials {
+            builder = builder.credentials(credentials);
+        }
+
+        Ok(Self {
+            transport: builder.build(),
+        })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, email: &Message) -> Result<(), MailerError> {
+        self.transport.send(email)?;
+        Ok(())
+    }
+}
+
+// Writes every e-mail as an `.eml` file into a directory
+pub struct FileMailer {
+    transport: FileTransport,
+}
+
+impl FileMailer {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self, MailerError> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            transport: FileTransport::new(dir),
+        })
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, email: &Message) -> Result<(), MailerError> {
+        self.transport.send(email)?;
+        Ok(())
+    }
+}
+
+// Keeps sent e-mails in memory, so they can be inspected instead of delivered
+#[derive(Default, Clone)]
+pub struct OutboxMailer {
+    outbox: Arc<Mutex<Vec<Message>>>,
+}
+
+impl OutboxMailer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The clones share the outbox, so the sent e-mails can be read from any of them
+    #[cfg(test)]
+    pub fn messages(&self) -> Vec<Message> {
+        self.outbox.lock().unwrap().clone()
+    }
+}
+
+impl Mailer for OutboxMailer {
+    fn send(&self, email: &Message) -> Result<(), MailerError> {
+        self.outbox.lock().unwrap().push(email.clone());
+        Ok(())
+    }
+}
+
+// Build the mailer selected by `MAIL_TRANSPORT` (smtp, file or outbox)
+pub fn mailer_from_env() -> Result<Arc<dyn Mailer>, MailerError> {
+    let transport = env::var("MAIL_TRANSPORT").unwrap_or("smtp".to_string());
+
+    match transport.to_lowercase().as_str() {
+        "smtp" => {
+            let host = env::var("SMTP_HOST").unwrap_or("smtp.gmail.com".to_string());
+            let port = match env::var("SMTP_PORT") {
+                Ok(port) => Some(port.parse::<u16>()?),
+                Err(_) => None,
+            };
+            let tls = env::var("SMTP_TLS")
+                .unwrap_or("tls".to_string())
+                .parse::<SmtpTls>()?;
+            let credentials = match (env::var("SMTP_USERNAME"), env::var("SMTP_PASSWORD")) {
+                (Ok(username), Ok(password)) => Some(Credentials::new(username, password)),
+                _ => None,
+            };
+
+            Ok(Arc::new(SmtpMailer::new(&host, port, tls, credentials)?))
+        }
+        "file" => {
+            let dir = env::var("MAIL_FILE_DIR").unwrap_or("mails".to_string());
+            Ok(Arc::new(FileMailer::new(dir)?))
+        }
+        "outbox" => Ok(Arc::new(OutboxMailer::new())),
+        other => Err(format!("Unknown MAIL_TRANSPORT: {}", other).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(to: &str) -> Message {
+        Message::builder()
+            .from("noreply@library-basement.com".parse().unwrap())
+            .to(to.parse().unwrap())
+            .subject("Teszt")
+            .body(String::from("Teszt levél"))
+            .unwrap()
+    }
+
+    #[test]
+    fn outbox_keeps_sent_messages_in_order() {
+        let outbox = OutboxMailer::new();
+        let mailer: Arc<dyn Mailer> = Arc::new(outbox.clone());
+
+        mailer.send(&message("first@example.com")).unwrap();
+        mailer.send(&message("second@example.com")).unwrap();
+
+        let recipients = outbox
+            .messages()
+            .iter()
+            .map(|message| message.envelope().to()[0].to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(recipients, ["first@example.com", "second@example.com"]);
+    }
+
+    #[test]
+    fn smtp_tls_rejects_unknown_modes() {
+        assert_eq!("STARTTLS".parse::<SmtpTls>(), Ok(SmtpTls::StartTls));
+        assert_eq!(
+            "ssl".parse::<SmtpTls>(),
+            Err("Unknown SMTP_TLS: ssl".to_string())
+        );
+    }
+}
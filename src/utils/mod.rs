@@ -1,4 +1,15 @@
+pub mod client_ip;
 pub mod credentials_hashing;
 pub mod email;
+pub mod export_link;
 pub mod jwt;
+pub mod login_guard;
+pub mod mail_queue;
+pub mod mailer;
+pub mod one_time_token;
+pub mod password_policy;
 pub mod redis;
+pub mod refresh_token;
+pub mod signing;
+pub mod totp;
+pub mod validation;
@@ -0,0 +1,152 @@
+extern crate redis;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use sha2::{Digest, Sha256};
+
+use super::email::Token;
+
+const LOGIN_CODE_TTL_SECS: u64 = 60 * 10;
+const LOGIN_CODE_MAX_ATTEMPTS: i64 = 5;
+const RESET_TOKEN_TTL_SECS: u64 = 60 * 60;
+
+// Delete the key only if it still holds the expected hash, so a code can't be used twice
+const CONSUME_SCRIPT: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+";
+
+// Only hashes are stored, a leaked Redis dump doesn't hand out working tokens
+pub(crate) fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.trim().as_bytes()))
+}
+
+// Six digit codes for logging in, always checked together with the account
+pub struct LoginCode;
+
+impl LoginCode {
+    // Create a new code, the previous one and its failed attempts are dropped
+    pub async fn issue(con: &mut ConnectionManager, user_id: i32) -> redis::RedisResult<String> {
+        let code = Token::generate_six_digit_number();
+        con.set_ex::<_, _, ()>(
+            format!("login-code:{user_id}"),
+            hash_token(&code),
+            LOGIN_CODE_TTL_SECS,
+        )
+        .await?;
+        con.del::<_, ()>(format!("login-code-attempts:{user_id}"))
+            .await?;
+        Ok(code)
+    }
+
+    // Use up the code, it is thrown away after too many wrong guesses
+    pub async fn consume(
+        con: &mut ConnectionManager,
+        user_id: i32,
+        code: &str,
+    ) -> redis::RedisResult<bool> {
+        let code_key = format!("login-code:{user_id}");
+        let attempts_key = format!("login-code-attempts:{user_id}");
+
+        let consumed = redis::Script::new(CONSUME_SCRIPT)
+            .key(&code_key)
+            .arg(hash_token(code))
+            .invoke_async::<i64>(con)
+            .await?
+            == 1;
+
+        if consumed {
+            con.del::<_, ()>(&attempts_key).await?;
+            return Ok(true);
+        }
+
+        let attempts = con.incr::<_, _, i64>(&attempts_key, 1).await?;
+        con.expire::<_, ()>(&attempts_key, LOGIN_CODE_TTL_SECS as i64)
+            .await?;
+        if attempts >= LOGIN_CODE_MAX_ATTEMPTS {
+            con.del::<_, ()>(&code_key).await?;
+        }
+        Ok(false)
+    }
+}
+
+// Long tokens for the password reset link, looked up by the token itself
+pub struct ResetToken;
+
+impl ResetToken {
+    // Create a new token, the previous one of the user stops working
+    pub async fn issue(con: &mut ConnectionManager, user_id: i32) -> redis::RedisResult<String> {
+        let user_key = format!("password-reset-user:{user_id}");
+        if let Some(previous_hash) = con.get::<_, Option<String>>(&user_key).await? {
+            con.del::<_, ()>(format!("password-reset:{previous_hash}"))
+                .await?;
+        }
+
+        let token = Token::generate_reset_token();
+        let token_hash = hash_token(&token);
+        con.set_ex::<_, _, ()>(
+            format!("password-reset:{token_hash}"),
+            user_id,
+            RESET_TOKEN_TTL_SECS,
+        )
+        .await?;
+        con.set_ex::<_, _, ()>(&user_key, &token_hash, RESET_TOKEN_TTL_SECS)
+            .await?;
+        Ok(token)
+    }
+
+    // The user the token was issued for, without using it up
+    pub async fn peek(con: &mut ConnectionManager, token: &str) -> redis::RedisResult<Option<i32>> {
+        con.get::<_, Option<i32>>(format!("password-reset:{}", hash_token(token)))
+            .await
+    }
+
+    // Use up the token, returns the user it was issued for
+    pub async fn consume(
+        con: &mut ConnectionManager,
+        token: &str,
+    ) -> redis::RedisResult<Option<i32>> {
+        let token_hash = hash_token(token);
+        let user_id = con
+            .get_del::<_, Option<i32>>(format!("password-reset:{token_hash}"))
+            .await?;
+
+        if let Some(user_id) = user_id {
+            con.del::<_, ()>(format!("password-reset-user:{user_id}"))
+                .await?;
+        }
+        Ok(user_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_sha256_of_the_token_is_stored() {
+        assert_eq!(
+            hash_token("123456"),
+            "8d969eef6ecad3c29a3a629280e686cf0c3f5d5a86aff3ca12020c923adc6c92"
+        );
+        assert_ne!(hash_token("123456"), hash_token("123457"));
+    }
+
+    #[test]
+    fn pasted_tokens_match_despite_surrounding_whitespace() {
+        assert_eq!(hash_token(" 123456\n"), hash_token("123456"));
+    }
+
+    #[test]
+    fn issued_tokens_have_the_expected_shape() {
+        let code = Token::generate_six_digit_number();
+        assert_eq!(code.len(), 6);
+        assert!(code.chars().all(|c| c.is_ascii_digit()));
+
+        let token = Token::generate_reset_token();
+        assert_eq!(token.len(), 32);
+        assert!(token.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_ne!(token, Token::generate_reset_token());
+    }
+}
@@ -0,0 +1,161 @@
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::sync::OnceLock;
+use validator::{ValidationError, ValidationErrors};
+
+use crate::error::AppError;
+
+// SHA-1 hashes of common and leaked passwords, grouped by their first five characters
+// like the range API of Have I Been Pwned, so the list can be swapped for a dump of it
+const BREACHED_PASSWORDS: &str = include_str!("../../data/breached_passwords.txt");
+
+const DEFAULT_MIN_LENGTH: usize = 10;
+// zxcvbn score from 0 to 4, 3 means it takes about 10^10 guesses
+const DEFAULT_MIN_SCORE: u8 = 3;
+
+pub struct PasswordPolicy {
+    min_length: usize,
+    min_score: u8,
+}
+
+impl PasswordPolicy {
+    // `PASSWORD_MIN_LENGTH` and `PASSWORD_MIN_SCORE` override the defaults
+    pub fn get() -> &'static Self {
+        static POLICY: OnceLock<PasswordPolicy> = OnceLock::new();
+        POLICY.get_or_init(|| Self {
+            min_length: env::var("PASSWORD_MIN_LENGTH")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(DEFAULT_MIN_LENGTH),
+            min_score: env::var("PASSWORD_MIN_SCORE")
+                .ok()
+                .and_then(|value| value.parse::<u8>().ok())
+                .map(|score| score.min(4))
+                .unwrap_or(DEFAULT_MIN_SCORE),
+        })
+    }
+
+    // Check a new password, every broken rule is listed under `field`
+    pub fn check(
+        &self,
+        field: &'static str,
+        password: &str,
+        username: &str,
+        email: &str,
+    ) -> Result<(), AppError> {
+        let mut errors = ValidationErrors::new();
+
+        if password.chars().count() < self.min_length {
+            let mut error = ValidationError::new("password_too_short");
+            error.add_param("min".into(), &self.min_length);
+            errors.add(field, error);
+        }
+
+        let email_name = email.split('@').next().unwrap_or(email);
+        let score = zxcvbn::zxcvbn(password, &[username, email_name]).score();
+        if u8::from(score) < self.min_score {
+            errors.add(field, ValidationError::new("password_too_weak"));
+        }
+
+        let lowercase = password.to_lowercase();
+        if [username, email, email_name]
+            .iter()
+            .any(|identity| !identity.is_empty() && lowercase == identity.to_lowercase())
+        {
+            errors.add(field, ValidationError::new("password_matches_identity"));
+        }
+
+        if is_breached(password) || is_breached(&lowercase) {
+            errors.add(field, ValidationError::new("password_breached"));
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(AppError::from(errors)),
+        }
+    }
+}
+
+// Only the hash of the password is looked up, never the password itself
+fn is_breached(password: &str) -> bool {
+    static RANGES: OnceLock<HashMap<&'static str, HashSet<&'static str>>> = OnceLock::new();
+    let ranges = RANGES.get_or_init(|| {
+        let mut ranges = HashMap::<&str, HashSet<&str>>::new();
+        for hash in BREACHED_PASSWORDS.lines().map(str::trim) {
+            if hash.len() == 40 {
+                let (prefix, suffix) = hash.split_at(5);
+                ranges.entry(prefix).or_default().insert(suffix);
+            }
+        }
+        ranges
+    });
+
+    let hash = hex::encode_upper(Sha1::digest(password.as_bytes()));
+    let (prefix, suffix) = hash.split_at(5);
+    ranges
+        .get(prefix)
+        .is_some_and(|range| range.contains(suffix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: PasswordPolicy = PasswordPolicy {
+        min_length: DEFAULT_MIN_LENGTH,
+        min_score: DEFAULT_MIN_SCORE,
+    };
+
+    // The codes of the broken rules, in the order they are checked
+    fn broken_rules(password: &str, username: &str, email: &str) -> Vec<String> {
+        match POLICY.check("password", password, username, email) {
+            Ok(()) => Vec::new(),
+            Err(AppError::Validation(errors)) => errors.field_errors()["password"]
+                .iter()
+                .map(|error| error.code.to_string())
+                .collect(),
+            Err(e) => panic!("unexpected error: {e:?}"),
+        }
+    }
+
+    #[test]
+    fn strong_password_passes() {
+        assert!(broken_rules("Tz8#kQ2v!mWp9rL", "anna", "anna@example.com").is_empty());
+    }
+
+    #[test]
+    fn every_broken_rule_is_listed() {
+        assert_eq!(
+            broken_rules("iloveyou", "anna", "anna@example.com"),
+            [
+                "password_too_short",
+                "password_too_weak",
+                "password_breached"
+            ]
+        );
+    }
+
+    #[test]
+    fn breached_list_is_checked_case_insensitively() {
+        assert!(is_breached("iloveyou"));
+        assert!(broken_rules("ILOVEYOU", "anna", "anna@example.com")
+            .contains(&"password_breached".to_string()));
+        assert!(!is_breached("Tz8#kQ2v!mWp9rL"));
+    }
+
+    #[test]
+    fn password_cant_be_the_username_or_email() {
+        for password in [
+            "KovacsAnna1987",
+            "kovacs.anna.1987@example.com",
+            "Kovacs.Anna.1987",
+        ] {
+            assert!(
+                broken_rules(password, "kovacsanna1987", "kovacs.anna.1987@example.com")
+                    .contains(&"password_matches_identity".to_string()),
+                "{password}"
+            );
+        }
+    }
+}
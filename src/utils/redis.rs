@@ -1,30 +1,96 @@
 extern crate redis;
-use redis::Commands;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+
+use crate::error::AppError;
+
+use super::one_time_token::hash_token;
 
 pub struct Redis;
 
 impl Redis {
-    pub fn set_token_to_user(
-        con: &mut redis::Connection,
+    // The key of a token handed out to a user, only its hash is stored like the one-time tokens
+    pub fn token_key(prefix: &str, token: &str) -> String {
+        format!("{prefix}:{}", hash_token(token))
+    }
+
+    // Set in one command, the token can't be left without an expiry
+    pub async fn set_token_to_user_with_expiry(
+        con: &mut ConnectionManager,
         user_id: u32,
-        token: &str,
+        token_key: &str,
+        seconds: u64,
     ) -> redis::RedisResult<()> {
-        con.set::<_, _, String>(token, format!("user:{user_id}"))?;
-        con.expire::<_, ()>(token, 600)?;
+        con.set_ex::<_, _, ()>(token_key, format!("user:{user_id}"), seconds)
+            .await
+    }
 
-        Ok(())
+    // None if the token doesn't exist or has expired
+    pub async fn get_user_id_by_token(
+        con: &mut ConnectionManager,
+        token: &str,
+    ) -> Result<Option<i32>, AppError> {
+        let redis_value = con.get::<_, Option<String>>(token).await?;
+        Self::parse_user_id(redis_value)
     }
 
-    pub fn get_user_id_by_token(
-        con: &mut redis::Connection,
+    // Like `get_user_id_by_token`, but the token is used up in the same command, so of two
+    // concurrent requests only one gets the user
+    pub async fn take_user_id_by_token(
+        con: &mut ConnectionManager,
         token: &str,
-    ) -> redis::RedisResult<i32> {
-        let is_exists = con.exists::<_, bool>(&token)?;
-        if is_exists {
-            let redis_value = con.get::<_, String>(&token)?;
-            let user_id = redis_value[5..].parse::<i32>().unwrap();
-            return Ok(user_id);
+    ) -> Result<Option<i32>, AppError> {
+        let redis_value = con.get_del::<_, Option<String>>(token).await?;
+        Self::parse_user_id(redis_value)
+    }
+
+    fn parse_user_id(redis_value: Option<String>) -> Result<Option<i32>, AppError> {
+        let Some(redis_value) = redis_value else {
+            return Ok(None);
+        };
+
+        let user_id = redis_value
+            .strip_prefix("user:")
+            .and_then(|user_id| user_id.parse::<i32>().ok());
+        match user_id {
+            Some(user_id) => Ok(Some(user_id)),
+            None => Err(AppError::Internal(format!(
+                "invalid token value: {redis_value}"
+            ))),
+        }
+    }
+
+    // Start a cooldown, returns the remaining seconds if one is already running
+    pub async fn try_start_cooldown(
+        con: &mut ConnectionManager,
+        key: &str,
+        seconds: u64,
+    ) -> redis::RedisResult<Option<i64>> {
+        let started = redis::cmd("SET")
+            .arg(key)
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(seconds)
+            .query_async::<Option<String>>(con)
+            .await?
+            .is_some();
+
+        if started {
+            return Ok(None);
         }
-        Ok(-1) // Not exists
+        Ok(Some(con.ttl::<_, i64>(key).await?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_keys_hold_the_hash_not_the_token() {
+        let key = Redis::token_key("2fa-pending", "k3F9aX7q2m");
+        assert_eq!(key, format!("2fa-pending:{}", hash_token("k3F9aX7q2m")));
+        assert!(!key.contains("k3F9aX7q2m"));
     }
 }
@@ -0,0 +1,434 @@
+extern crate redis;
+use actix_web::{http::header::USER_AGENT, HttpRequest};
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+use crate::error::AppError;
+
+use super::client_ip::ClientIp;
+use super::email::Token;
+use super::jwt::{generate_jwt_token, revoke_jti, JwtKeys};
+
+const REFRESH_TOKEN_TTL_SECS: u64 = 60 * 60 * 24 * 30;
+
+// Take the token and mark it used in one step, so a replay always finds either the token or the
+// marker. Returns the token data, or the family of an already used token
+const ROTATE_SCRIPT: &str = r"
+local data = redis.call('GET', KEYS[1])
+if data then
+    redis.call('DEL', KEYS[1])
+    redis.call('SET', KEYS[2], cjson.decode(data).family_id, 'EX', ARGV[1])
+    return {'valid', data}
+end
+local family_id = redis.call('GET', KEYS[2])
+if family_id then
+    return {'used', family_id}
+end
+return {}
+";
+
+#[derive(Debug, Serialize)]
+pub struct AuthTokens {
+    pub access_token: String,
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RefreshTokenData {
+    user_id: usize,
+    family_id: String,
+}
+
+// Every login starts a new family (a session), rotation keeps only its newest token valid
+#[derive(Debug, Serialize, Deserialize)]
+struct RefreshTokenFamily {
+    user_id: usize,
+    refresh_token: String,
+    access_jti: String,
+    access_exp: usize,
+    user_agent: String,
+    ip: String,
+    created_at: i64,
+}
+
+// Device the login happened from
+pub struct SessionInfo {
+    pub user_agent: String,
+    pub ip: String,
+}
+
+impl SessionInfo {
+    pub fn from_request(req: &HttpRequest) -> Self {
+        let user_agent = req
+            .headers()
+            .get(USER_AGENT)
+            .and_then(|header| header.to_str().ok())
+            .unwrap_or("")
+            .to_string();
+        let ip = ClientIp::of(req);
+
+        Self { user_agent, ip }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Session {
+    pub id: String,
+    pub user_agent: String,
+    pub ip: String,
+    pub created_at: i64,
+    pub last_seen: i64,
+    pub current: bool,
+}
+
+pub struct RefreshToken;
+
+impl RefreshToken {
+    // Issue an access and refresh token pair for a fresh login
+    pub async fn issue(
+        con: &mut ConnectionManager,
+        user_id: usize,
+        info: SessionInfo,
+        jwt_keys: &JwtKeys,
+    ) -> Result<AuthTokens, AppError> {
+        let family_id = Token::generate_reset_token();
+        let now = chrono::Utc::now().timestamp();
+        con.set_ex::<_, _, ()>(
+            format!("session-last-seen:{family_id}"),
+            now,
+            REFRESH_TOKEN_TTL_SECS,
+        )
+        .await?;
+
+        let family = RefreshTokenFamily {
+            user_id,
+            refresh_token: String::new(),
+            access_jti: String::new(),
+            access_exp: 0,
+            user_agent: info.user_agent,
+            ip: info.ip,
+            created_at: now,
+        };
+        Self::issue_in_family(con, &family_id, family, jwt_keys).await
+    }
+
+    // Exchange a refresh token for a new pair, reusing an old one revokes the whole family
+    pub async fn rotate(
+        con: &mut ConnectionManager,
+        refresh_token: &str,
+        jwt_keys: &JwtKeys,
+    ) -> Result<AuthTokens, AppError> {
+        // Of two concurrent refreshes with the same token only one gets it
+        let result = redis::Script::new(ROTATE_SCRIPT)
+            .key(format!("refresh:{refresh_token}"))
+            .key(format!("refresh-used:{refresh_token}"))
+            .arg(REFRESH_TOKEN_TTL_SECS)
+            .invoke_async::<Vec<String>>(con)
+            .await?;
+        let data = match result.as_slice() {
+            [status, data] if status == "valid" => serde_json::from_str::<RefreshTokenData>(data)?,
+            [status, family_id] if status == "used" => {
+                Self::revoke_family(con, family_id).await?;
+                return Err(AppError::Unauthorized("invalid_refresh_token"));
+            }
+            _ => return Err(AppError::Unauthorized("invalid_refresh_token")),
+        };
+
+        let Some(family) = Self::get_family(con, &data.family_id).await? else {
+            return Err(AppError::Unauthorized("invalid_refresh_token"));
+        };
+
+        revoke_jti(con, &family.access_jti, family.access_exp).await?;
+
+        Self::issue_in_family(con, &data.family_id, family, jwt_keys).await
+    }
+
+    // Revoke the family of a refresh token, if it belongs to the user
+    pub async fn revoke(
+        con: &mut ConnectionManager,
+        user_id: usize,
+        refresh_token: &str,
+    ) -> Result<(), AppError> {
+        let Some(data) = con
+            .get::<_, Option<String>>(format!("refresh:{refresh_token}"))
+            .await?
+        else {
+            return Ok(());
+        };
+        let data = serde_json::from_str::<RefreshTokenData>(&data)?;
+
+        if data.user_id == user_id {
+            Self::revoke_family(con, &data.family_id).await?;
+        }
+        Ok(())
+    }
+
+    // Revoke every refresh token family of the user
+    pub async fn revoke_all(con: &mut ConnectionManager, user_id: usize) -> Result<(), AppError> {
+        let families = con
+            .smembers::<_, Vec<String>>(format!("refresh-families:{user_id}"))
+            .await?;
+        for family_id in families.iter() {
+            Self::revoke_family(con, family_id).await?;
+        }
+        Ok(())
+    }
+
+    // List the live sessions of the user
+    pub async fn get_sessions(
+        con: &mut ConnectionManager,
+        user_id: usize,
+        current_session_id: &str,
+    ) -> Result<Vec<Session>, AppError> {
+        let families_key = format!("refresh-families:{user_id}");
+        let families = con.smembers::<_, Vec<String>>(&families_key).await?;
+
+        let mut sessions = Vec::new();
+        for family_id in families {
+            // Expired sessions are dropped from the set as they are found
+            let Some(family) = Self::get_family(con, &family_id).await? else {
+                con.srem::<_, _, ()>(&families_key, &family_id).await?;
+                continue;
+            };
+            let last_seen = con
+                .get::<_, Option<i64>>(format!("session-last-seen:{family_id}"))
+                .await?
+                .unwrap_or(family.created_at);
+
+            sessions.push(Session {
+                current: family_id == current_session_id,
+                id: family_id,
+                user_agent: family.user_agent,
+                ip: family.ip,
+                created_at: family.created_at,
+                last_seen,
+            });
+        }
+
+        sessions.sort_by_key(|session| std::cmp::Reverse(session.last_seen));
+        Ok(sessions)
+    }
+
+    // Log out a single device of the user
+    pub async fn revoke_session(
+        con: &mut ConnectionManager,
+        user_id: usize,
+        session_id: &str,
+    ) -> Result<(), AppError> {
+        match Self::get_family(con, session_id).await? {
+            Some(family) if family.user_id == user_id => Self::revoke_family(con, session_id).await,
+            _ => Err(AppError::NotFound("session_not_found")),
+        }
+    }
+
+    // Refresh the last activity of a session, returns false if it was logged out
+    pub async fn touch_session(
+        con: &mut ConnectionManager,
+        session_id: &str,
+    ) -> redis::RedisResult<bool> {
+        if !con
+            .exists::<_, bool>(format!("refresh-family:{session_id}"))
+            .await?
+        {
+            return Ok(false);
+        }
+
+        con.set_ex::<_, _, ()>(
+            format!("session-last-seen:{session_id}"),
+            chrono::Utc::now().timestamp(),
+            REFRESH_TOKEN_TTL_SECS,
+        )
+        .await?;
+        Ok(true)
+    }
+
+    async fn revoke_family(con: &mut ConnectionManager, family_id: &str) -> Result<(), AppError> {
+        let Some(family) = Self::get_family(con, family_id).await? else {
+            return Ok(());
+        };
+
+        con.del::<_, ()>(format!("refresh:{}", family.refresh_token))
+            .await?;
+        con.del::<_, ()>(format!("refresh-family:{family_id}"))
+            .await?;
+        con.del::<_, ()>(format!("session-last-seen:{family_id}"))
+            .await?;
+        con.srem::<_, _, ()>(format!("refresh-families:{}", family.user_id), family_id)
+            .await?;
+        revoke_jti(con, &family.access_jti, family.access_exp).await?;
+        Ok(())
+    }
+
+    async fn get_family(
+        con: &mut ConnectionManager,
+        family_id: &str,
+    ) -> Result<Option<RefreshTokenFamily>, AppError> {
+        let family = con
+            .get::<_, Option<String>>(format!("refresh-family:{family_id}"))
+            .await?;
+        match family {
+            Some(family) => Ok(Some(serde_json::from_str(&family)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn issue_in_family(
+        con: &mut ConnectionManager,
+        family_id: &str,
+        mut family: RefreshTokenFamily,
+        jwt_keys: &JwtKeys,
+    ) -> Result<AuthTokens, AppError> {
+        let (access_token, claims) = generate_jwt_token(family.user_id, family_id, jwt_keys)?;
+        let refresh_token = Token::generate_reset_token();
+
+        con.set_ex::<_, _, ()>(
+            format!("refresh:{refresh_token}"),
+            serde_json::to_string(&RefreshTokenData {
+                user_id: family.user_id,
+                family_id: family_id.to_string(),
+            })?,
+            REFRESH_TOKEN_TTL_SECS,
+        )
+        .await?;
+
+        // Renewed with every token, a session kept alive by refreshing stays listed and revocable
+        let families_key = format!("refresh-families:{}", family.user_id);
+        con.sadd::<_, _, ()>(&families_key, family_id).await?;
+        con.expire::<_, ()>(&families_key, REFRESH_TOKEN_TTL_SECS as i64)
+            .await?;
+
+        family.refresh_token = refresh_token.clone();
+        family.access_jti = claims.jti;
+        family.access_exp = claims.exp;
+        con.set_ex::<_, _, ()>(
+            format!("refresh-family:{family_id}"),
+            serde_json::to_string(&family)?,
+            REFRESH_TOKEN_TTL_SECS,
+        )
+        .await?;
+
+        Ok(AuthTokens {
+            access_token,
+            refresh_token,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    // Run with `cargo test -- --ignored` and `REDIS_URL` pointing to a disposable Redis
+    async fn redis() -> ConnectionManager {
+        let url = env::var("REDIS_URL").expect("REDIS_URL must be set");
+        ConnectionManager::new(redis::Client::open(url).unwrap())
+            .await
+            .unwrap()
+    }
+
+    fn jwt_keys() -> JwtKeys {
+        env::set_var("SECRET_AUTH_KEY", "refresh-token-test");
+        JwtKeys::from_env().unwrap()
+    }
+
+    fn session_info() -> SessionInfo {
+        SessionInfo {
+            user_agent: "test".to_string(),
+            ip: "192.0.2.1".to_string(),
+        }
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs Redis at REDIS_URL"]
+    async fn session_refreshed_after_the_set_expired_stays_listed_and_revocable() {
+        let mut con = redis().await;
+        let jwt_keys = jwt_keys();
+        let user_id = 1_000_000_000 + rand::random::<u16>() as usize;
+
+        let tokens = RefreshToken::issue(&mut con, user_id, session_info(), &jwt_keys)
+            .await
+            .unwrap();
+        // The set was only renewed at login before, 30 days later it was gone
+        con.del::<_, ()>(format!("refresh-families:{user_id}"))
+            .await
+            .unwrap();
+        let tokens = RefreshToken::rotate(&mut con, &tokens.refresh_token, &jwt_keys)
+            .await
+            .unwrap();
+
+        let sessions = RefreshToken::get_sessions(&mut con, user_id, "")
+            .await
+            .unwrap();
+        assert_eq!(sessions.len(), 1);
+
+        RefreshToken::revoke_all(&mut con, user_id).await.unwrap();
+        assert!(RefreshToken::get_sessions(&mut con, user_id, "")
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(
+            RefreshToken::rotate(&mut con, &tokens.refresh_token, &jwt_keys)
+                .await
+                .is_err()
+        );
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs Redis at REDIS_URL"]
+    async fn replayed_token_revokes_the_session() {
+        let mut con = redis().await;
+        let jwt_keys = jwt_keys();
+        let user_id = 1_000_000_000 + rand::random::<u16>() as usize;
+
+        let first = RefreshToken::issue(&mut con, user_id, session_info(), &jwt_keys)
+            .await
+            .unwrap();
+        let second = RefreshToken::rotate(&mut con, &first.refresh_token, &jwt_keys)
+            .await
+            .unwrap();
+
+        assert!(
+            RefreshToken::rotate(&mut con, &first.refresh_token, &jwt_keys)
+                .await
+                .is_err()
+        );
+        assert!(
+            RefreshToken::rotate(&mut con, &second.refresh_token, &jwt_keys)
+                .await
+                .is_err()
+        );
+        assert!(RefreshToken::get_sessions(&mut con, user_id, "")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs Redis at REDIS_URL"]
+    async fn expired_sessions_are_pruned_from_the_set() {
+        let mut con = redis().await;
+        let jwt_keys = jwt_keys();
+        let user_id = 1_000_000_000 + rand::random::<u16>() as usize;
+
+        RefreshToken::issue(&mut con, user_id, session_info(), &jwt_keys)
+            .await
+            .unwrap();
+        let families_key = format!("refresh-families:{user_id}");
+        con.sadd::<_, _, ()>(&families_key, "expired-family")
+            .await
+            .unwrap();
+
+        let sessions = RefreshToken::get_sessions(&mut con, user_id, "")
+            .await
+            .unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert!(!con
+            .sismember::<_, _, bool>(&families_key, "expired-family")
+            .await
+            .unwrap());
+
+        RefreshToken::revoke_all(&mut con, user_id).await.unwrap();
+    }
+}
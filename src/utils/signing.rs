@@ -0,0 +1,69 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::env;
+
+// HMAC-SHA256 signatures of the values handed out to clients, so they can't be forged. The key
+// is read from the given variable, `SECRET_AUTH_KEY` is used if it isn't set. The purpose is
+// signed with every value, so a signature made for one purpose is never valid for another, even
+// when they share the key
+#[derive(Clone)]
+pub struct Signer {
+    secret: String,
+    purpose: &'static str,
+}
+
+impl Signer {
+    pub fn from_env(name: &str, purpose: &'static str) -> Result<Self, String> {
+        let secret = env::var(name)
+            .or_else(|_| env::var("SECRET_AUTH_KEY"))
+            .map_err(|_| format!("{name} or SECRET_AUTH_KEY must be set"))?;
+        Ok(Self { secret, purpose })
+    }
+
+    #[cfg(test)]
+    pub fn new(secret: &str, purpose: &'static str) -> Self {
+        Self {
+            secret: secret.to_string(),
+            purpose,
+        }
+    }
+
+    fn mac(&self, value: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(self.purpose.as_bytes());
+        mac.update(b"\0");
+        mac.update(value.as_bytes());
+        mac
+    }
+
+    pub fn sign(&self, value: &str) -> String {
+        hex::encode(self.mac(value).finalize().into_bytes())
+    }
+
+    // Compared in constant time
+    pub fn verify(&self, value: &str, signature: &str) -> bool {
+        let Ok(signature) = hex::decode(signature) else {
+            return false;
+        };
+        self.mac(value).verify_slice(&signature).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_only_verifies_for_the_same_value_and_key() {
+        let signer = Signer::new("secret", "guest-cart");
+        let signature = signer.sign("cart-1");
+
+        assert!(signer.verify("cart-1", &signature));
+        assert!(!signer.verify("cart-2", &signature));
+        assert!(!Signer::new("other", "guest-cart").verify("cart-1", &signature));
+        assert!(!Signer::new("secret", "export-link").verify("cart-1", &signature));
+        assert!(!signer.verify("cart-1", &signature[..signature.len() - 2]));
+        assert!(!signer.verify("cart-1", "not hex"));
+    }
+}
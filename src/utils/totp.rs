@@ -0,0 +1,132 @@
+extern crate redis;
+use rand::{distributions::Alphanumeric, Rng};
+use redis::aio::ConnectionManager;
+use std::error::Error;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+const ISSUER: &str = "Library Basement";
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_HALF_LEN: usize = 5;
+// A code stays valid for the previous, current and next 30 second step
+const USED_CODE_TTL_SECS: u64 = 90;
+
+pub struct Totp;
+
+impl Totp {
+    // Random 160 bit secret in base32, as the authenticator apps expect it
+    pub fn generate_secret() -> String {
+        Secret::generate_secret().to_encoded().to_string()
+    }
+
+    fn build(secret: &str, account_name: &str) -> Result<TOTP, Box<dyn Error>> {
+        let secret = Secret::Encoded(secret.to_string()).to_bytes()?;
+        let totp = TOTP::new(
+            Algorithm::SHA1,
+            6,
+            1,
+            30,
+            secret,
+            Some(ISSUER.to_string()),
+            account_name.replace(':', ""),
+        )?;
+        Ok(totp)
+    }
+
+    pub fn get_url(secret: &str, account_name: &str) -> Result<String, Box<dyn Error>> {
+        Ok(Self::build(secret, account_name)?.get_url())
+    }
+
+    // Base64 encoded PNG of the otpauth URI
+    pub fn get_qr_code(secret: &str, account_name: &str) -> Result<String, Box<dyn Error>> {
+        Ok(Self::build(secret, account_name)?.get_qr_base64()?)
+    }
+
+    // Check a code, every code can only be used once by the user
+    pub async fn check(
+        con: &mut ConnectionManager,
+        user_id: i32,
+        secret: &str,
+        code: &str,
+    ) -> Result<bool, Box<dyn Error>> {
+        if !Self::build(secret, "")?.check_current(code)? {
+            return Ok(false);
+        }
+
+        let is_first_use = redis::cmd("SET")
+            .arg(format!("totp-used:{user_id}:{code}"))
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(USED_CODE_TTL_SECS)
+            .query_async::<Option<String>>(con)
+            .await?
+            .is_some();
+        Ok(is_first_use)
+    }
+
+    // One-time codes for logging in without the authenticator, e.g. "k3f9a-x7q2m"
+    pub fn generate_recovery_codes() -> Vec<String> {
+        (0..RECOVERY_CODE_COUNT)
+            .map(|_| {
+                let code = rand::thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(RECOVERY_CODE_HALF_LEN * 2)
+                    .map(char::from)
+                    .collect::<String>()
+                    .to_lowercase();
+                let (first, second) = code.split_at(RECOVERY_CODE_HALF_LEN);
+                format!("{first}-{second}")
+            })
+            .collect()
+    }
+
+    // Checking a recovery code is a full password hash per stored code, so only inputs that can
+    // be one are checked
+    pub fn is_recovery_code(code: &str) -> bool {
+        let Some((first, second)) = code.split_once('-') else {
+            return false;
+        };
+        [first, second].iter().all(|half| {
+            half.len() == RECOVERY_CODE_HALF_LEN && half.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovery_codes_are_unique_and_in_the_checked_format() {
+        let codes = Totp::generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        assert!(codes.iter().all(|code| Totp::is_recovery_code(code)));
+
+        let mut unique = codes.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), codes.len());
+    }
+
+    #[test]
+    fn only_recovery_code_shaped_inputs_are_hash_checked() {
+        assert!(Totp::is_recovery_code("k3f9a-x7q2m"));
+        assert!(!Totp::is_recovery_code("123456"));
+        assert!(!Totp::is_recovery_code("k3f9a-x7q2"));
+        assert!(!Totp::is_recovery_code("k3f9ax7q2m"));
+        assert!(!Totp::is_recovery_code("k3f9a-x7q2m-"));
+        assert!(!Totp::is_recovery_code("k3f9!-x7q2m"));
+    }
+
+    #[test]
+    fn current_code_of_the_secret_is_accepted() {
+        let secret = Totp::generate_secret();
+        let totp = Totp::build(&secret, "user@example.com").unwrap();
+        let code = totp.generate_current().unwrap();
+
+        assert!(totp.check_current(&code).unwrap());
+        assert!(Totp::get_url(&secret, "user@example.com")
+            .unwrap()
+            .starts_with("otpauth://totp/Library%20Basement:user%40example.com"));
+    }
+}
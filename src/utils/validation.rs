@@ -0,0 +1,142 @@
+use validator::ValidationError;
+
+use crate::i18n::Locale;
+
+// Custom rules for the request structs, the codes are looked up as `validation_<code>`
+
+// 3-30 characters, letters, digits, dots, dashes and underscores
+pub fn validate_username(username: &str) -> Result<(), ValidationError> {
+    let length = username.chars().count();
+    let is_valid_charset = username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+
+    if !(3..=30).contains(&length) || !is_valid_charset {
+        return Err(ValidationError::new("username"));
+    }
+    Ok(())
+}
+
+// E.164 format, e.g. +36301234567
+pub fn validate_phone_number(phone_number: &str) -> Result<(), ValidationError> {
+    let Some(digits) = phone_number.strip_prefix('+') else {
+        return Err(ValidationError::new("phone_number"));
+    };
+
+    let is_valid = (8..=15).contains(&digits.len())
+        && digits.chars().all(|c| c.is_ascii_digit())
+        && !digits.starts_with('0');
+    if !is_valid {
+        return Err(ValidationError::new("phone_number"));
+    }
+    Ok(())
+}
+
+// Hungarian postal codes are four digits and never start with zero
+pub fn validate_postal_code(postal_code: &str) -> Result<(), ValidationError> {
+    let is_valid = postal_code.len() == 4
+        && postal_code.chars().all(|c| c.is_ascii_digit())
+        && !postal_code.starts_with('0');
+    if !is_valid {
+        return Err(ValidationError::new("postal_code"));
+    }
+    Ok(())
+}
+
+// ISBN-10 or ISBN-13 with a valid check digit, dashes and spaces are ignored
+pub fn validate_isbn(isbn: &str) -> Result<(), ValidationError> {
+    let chars = isbn
+        .chars()
+        .filter(|c| !matches!(c, '-' | ' '))
+        .collect::<Vec<char>>();
+
+    let is_valid = match chars.len() {
+        10 => {
+            let mut sum = 0;
+            for (i, c) in chars.iter().enumerate() {
+                let digit = match c {
+                    'X' | 'x' if i == 9 => 10,
+                    c => match c.to_digit(10) {
+                        Some(digit) => digit,
+                        None => return Err(ValidationError::new("isbn")),
+                    },
+                };
+                sum += digit * (10 - i as u32);
+            }
+            sum % 11 == 0
+        }
+        13 => {
+            let mut sum = 0;
+            for (i, c) in chars.iter().enumerate() {
+                let Some(digit) = c.to_digit(10) else {
+                    return Err(ValidationError::new("isbn"));
+                };
+                sum += if i % 2 == 0 { digit } else { digit * 3 };
+            }
+            sum % 10 == 0
+        }
+        _ => false,
+    };
+
+    if !is_valid {
+        return Err(ValidationError::new("isbn"));
+    }
+    Ok(())
+}
+
+pub fn validate_locale(locale: &str) -> Result<(), ValidationError> {
+    match Locale::parse(locale) {
+        Some(_) => Ok(()),
+        None => Err(ValidationError::new("locale")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usernames() {
+        assert!(validate_username("kovacs.anna_92").is_ok());
+        assert!(validate_username("ab").is_err());
+        assert!(validate_username(&"a".repeat(31)).is_err());
+        assert!(validate_username("kovács").is_err());
+        assert!(validate_username("anna kovacs").is_err());
+    }
+
+    #[test]
+    fn phone_numbers_in_e164() {
+        assert!(validate_phone_number("+36301234567").is_ok());
+        assert!(validate_phone_number("06301234567").is_err());
+        assert!(validate_phone_number("+06301234567").is_err());
+        assert!(validate_phone_number("+3630 123 4567").is_err());
+        assert!(validate_phone_number("+1234567").is_err());
+    }
+
+    #[test]
+    fn hungarian_postal_codes() {
+        assert!(validate_postal_code("1011").is_ok());
+        assert!(validate_postal_code("0123").is_err());
+        assert!(validate_postal_code("101").is_err());
+        assert!(validate_postal_code("10a1").is_err());
+    }
+
+    #[test]
+    fn isbn_check_digits() {
+        assert!(validate_isbn("0-306-40615-2").is_ok());
+        assert!(validate_isbn("0-8044-2957-X").is_ok());
+        assert!(validate_isbn("978-0-306-40615-7").is_ok());
+        assert!(validate_isbn("978 0 306 40615 7").is_ok());
+        assert!(validate_isbn("0-306-40615-3").is_err());
+        assert!(validate_isbn("978-0-306-40615-8").is_err());
+        assert!(validate_isbn("X-306-40615-2").is_err());
+        assert!(validate_isbn("12345").is_err());
+    }
+
+    #[test]
+    fn supported_locales() {
+        assert!(validate_locale("hu").is_ok());
+        assert!(validate_locale("en-US").is_ok());
+        assert!(validate_locale("de").is_err());
+    }
+}